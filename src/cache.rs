@@ -1,14 +1,23 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::Arc;
 use std::time::Duration;
 
-use moka::future::{Cache as MokaCache, CacheBuilder};
-use tracing::{trace,debug};
+use moka::sync::{Cache as MokaCache, CacheBuilder};
+use parking_lot::Mutex;
+use tracing::{debug, trace};
 
 use crate::drive::model::PikpakFile;
 
+/// Directory listing cache keyed by folder id.
+///
+/// Every invalidation bumps the generation of the folder, a listing fetched
+/// while the folder changed is older than the change and isn't cached.
 #[derive(Clone)]
 pub struct Cache {
     inner: MokaCache<String, Vec<PikpakFile>>,
+    // folder id -> number of invalidations
+    generations: Arc<Mutex<HashMap<String, u64>>>,
 }
 
 impl Cache {
@@ -16,35 +25,90 @@ impl Cache {
         let inner = CacheBuilder::new(max_capacity)
             .time_to_live(Duration::from_secs(ttl))
             .build();
-        Self { inner }
+        Self {
+            inner,
+            generations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Current generation of the folder, taken before listing it
+    pub fn generation(&self, folder_id: &str) -> u64 {
+        self.generations.lock().get(folder_id).copied().unwrap_or_default()
     }
 
-    #[allow(clippy::ptr_arg)]
-    pub fn get(&self, key: &String) -> Option<Vec<PikpakFile>> {
-        trace!(key = %key, "cache: get");
-        self.inner.get(key)
+    pub fn get(&self, folder_id: &str) -> Option<Vec<PikpakFile>> {
+        trace!(folder_id = %folder_id, "cache: get");
+        self.inner.get(folder_id)
     }
 
-    pub async fn insert(&self, key: String, value: Vec<PikpakFile>) {
-        trace!(key = %key, "cache: insert");
-        self.inner.insert(key, value).await;
+    /// Cache a listing fetched at `generation`, returns false if the folder
+    /// was invalidated since then
+    pub fn insert(&self, folder_id: String, value: Vec<PikpakFile>, generation: u64) -> bool {
+        let generations = self.generations.lock();
+        if generations.get(&folder_id).copied().unwrap_or_default() != generation {
+            debug!(folder_id = %folder_id, "cache: outdated listing dropped");
+            return false;
+        }
+        trace!(folder_id = %folder_id, "cache: insert");
+        self.inner.insert(folder_id, value);
+        true
     }
 
-    pub async fn invalidate(&self, path: &Path) {
-        let key = path.to_string_lossy().into_owned();
-        debug!(path = %path.display(), key = %key, "cache: invalidate");
-        self.inner.invalidate(&key).await;
+    pub fn invalidate(&self, folder_id: &str) {
+        debug!(folder_id = %folder_id, "cache: invalidate");
+        let mut generations = self.generations.lock();
+        *generations.entry(folder_id.to_string()).or_default() += 1;
+        self.inner.invalidate(folder_id);
     }
 
-    pub async fn invalidate_parent(&self, path: &Path) {
-        if let Some(parent) = path.parent() {
-            self.invalidate(parent).await;
-        }
+}
+
+/// Names known not to exist, keyed by parent inode
+#[derive(Clone)]
+pub struct NegativeCache {
+    inner: MokaCache<(u64, OsString), ()>,
+}
+
+impl NegativeCache {
+    pub fn new(max_capacity: u64, ttl: Duration) -> Self {
+        let inner = CacheBuilder::new(max_capacity).time_to_live(ttl).build();
+        Self { inner }
+    }
+
+    pub fn contains(&self, parent: u64, name: &OsStr) -> bool {
+        self.inner.contains_key(&(parent, name.to_os_string()))
     }
 
+    pub fn insert(&self, parent: u64, name: &OsStr) {
+        trace!(parent = parent, name = ?name, "negative cache: insert");
+        self.inner.insert((parent, name.to_os_string()), ());
+    }
 
-    pub fn invalidate_all(&self) {
-        trace!("cache: invalidate all");
-        self.inner.invalidate_all();
+    pub fn invalidate(&self, parent: u64, name: &OsStr) {
+        trace!(parent = parent, name = ?name, "negative cache: invalidate");
+        self.inner.invalidate(&(parent, name.to_os_string()));
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outdated_listings_are_dropped() {
+        let cache = Cache::new(10, 60);
+        let listing = vec![PikpakFile::new_root()];
+
+        // invalidated while the listing was fetched
+        let generation = cache.generation("f");
+        cache.invalidate("f");
+        assert!(!cache.insert("f".to_string(), listing.clone(), generation));
+        assert!(cache.get("f").is_none());
+
+        let generation = cache.generation("f");
+        assert!(cache.insert("f".to_string(), listing, generation));
+        assert_eq!(cache.get("f").unwrap().len(), 1);
+        // other folders keep their generation
+        assert_eq!(cache.generation("g"), 0);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tracing::{debug, error, info};
+
+const TMP_EXTENSION: &str = "tmp";
+
+#[derive(Debug)]
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // block file name -> entry
+    entries: HashMap<String, Entry>,
+    // last use -> block file name, least recently used first
+    lru: BTreeMap<u64, String>,
+    size: u64,
+    counter: u64,
+}
+
+impl State {
+    fn touch(&mut self, name: &str) -> bool {
+        let counter = self.counter + 1;
+        match self.entries.get_mut(name) {
+            Some(entry) => {
+                self.lru.remove(&entry.last_used);
+                entry.last_used = counter;
+                self.lru.insert(counter, name.to_string());
+                self.counter = counter;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        self.remove(&name);
+        self.counter += 1;
+        self.lru.insert(self.counter, name.clone());
+        self.entries.insert(
+            name,
+            Entry {
+                size,
+                last_used: self.counter,
+            },
+        );
+        self.size += size;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    /// Drop least recently used blocks until the cache fits in `max_size`,
+    /// returns the names of the dropped blocks
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let name = match self.lru.values().next() {
+                Some(name) => name.clone(),
+                None => break,
+            };
+            self.remove(&name);
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+/// Downloaded blocks kept on disk, shared by all file handles and remounts.
+///
+/// Blocks are keyed by file id, content version and block index, a file
+/// whose content changed gets a new version so stale blocks are never read
+/// and age out of the cache.
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    block_size: u64,
+    state: Mutex<State>,
+    next_tmp: AtomicU64,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_size: u64, block_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut blocks = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().map(|ext| ext == TMP_EXTENSION).unwrap_or(false) {
+                // left over by an interrupted write
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+                blocks.push((modified, name, metadata.len()));
+            }
+        }
+        blocks.sort();
+        let mut state = State::default();
+        for (_, name, size) in blocks {
+            state.insert(name, size);
+        }
+        let evicted = state.evict(max_size);
+        info!(
+            dir = %dir.display(),
+            blocks = state.entries.len(),
+            size = state.size,
+            "disk cache loaded"
+        );
+        let cache = Self {
+            dir,
+            max_size,
+            block_size,
+            state: Mutex::new(state),
+            next_tmp: AtomicU64::new(0),
+        };
+        cache.remove_files(evicted);
+        Ok(cache)
+    }
+
+    fn block_name(&self, file_id: &str, version: &str, index: u64) -> String {
+        let sanitize = |s: &str| -> String {
+            s.chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+                .collect()
+        };
+        format!(
+            "{}.{}.{}.{}",
+            sanitize(file_id),
+            sanitize(version),
+            self.block_size,
+            index
+        )
+    }
+
+    pub fn get(&self, file_id: &str, version: &str, index: u64) -> Option<Bytes> {
+        let name = self.block_name(file_id, version, index);
+        if !self.state.lock().touch(&name) {
+            return None;
+        }
+        match fs::read(self.dir.join(&name)) {
+            Ok(data) => {
+                debug!(block = %name, "disk cache hit");
+                Some(Bytes::from(data))
+            }
+            Err(err) => {
+                error!(block = %name, error = %err, "read cached block failed");
+                self.state.lock().remove(&name);
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, file_id: &str, version: &str, index: u64, data: &Bytes) {
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return;
+        }
+        let name = self.block_name(file_id, version, index);
+        let path = self.dir.join(&name);
+        let tmp_path = self.dir.join(format!(
+            "{}.{}.{}",
+            name,
+            self.next_tmp.fetch_add(1, Ordering::SeqCst),
+            TMP_EXTENSION
+        ));
+        if let Err(err) = fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, &path)) {
+            error!(block = %name, error = %err, "write cached block failed");
+            let _ = fs::remove_file(&tmp_path);
+            return;
+        }
+        let evicted = {
+            let mut state = self.state.lock();
+            state.insert(name, size);
+            state.evict(self.max_size)
+        };
+        self.remove_files(evicted);
+    }
+
+    fn remove_files(&self, names: Vec<String>) {
+        for name in names {
+            debug!(block = %name, "disk cache evict");
+            if let Err(err) = fs::remove_file(self.dir.join(&name)) {
+                error!(block = %name, error = %err, "remove cached block failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pikpak-fuse-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn evicts_least_recently_used_blocks() {
+        let dir = workdir("disk-cache-lru");
+        let cache = DiskCache::new(dir.clone(), 10, 4).unwrap();
+        cache.put("a", "v1", 0, &Bytes::from("aaaa"));
+        cache.put("b", "v1", 0, &Bytes::from("bbbb"));
+        // a is now used more recently than b
+        assert_eq!(cache.get("a", "v1", 0).unwrap(), Bytes::from("aaaa"));
+        cache.put("c", "v1", 0, &Bytes::from("cccc"));
+
+        assert!(cache.get("b", "v1", 0).is_none());
+        assert!(!dir.join(cache.block_name("b", "v1", 0)).exists());
+        assert_eq!(cache.get("a", "v1", 0).unwrap(), Bytes::from("aaaa"));
+        assert_eq!(cache.get("c", "v1", 0).unwrap(), Bytes::from("cccc"));
+        assert_eq!(cache.state.lock().size, 8);
+
+        // other versions of a file are different blocks, too large ones aren't kept
+        assert!(cache.get("a", "v2", 0).is_none());
+        cache.put("d", "v1", 0, &Bytes::from("ddddddddddd"));
+        assert!(cache.get("d", "v1", 0).is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reloads_blocks_on_restart() {
+        let dir = workdir("disk-cache-reload");
+        let cache = DiskCache::new(dir.clone(), 100, 4).unwrap();
+        cache.put("a", "v1", 0, &Bytes::from("aaaa"));
+        cache.put("a", "v1", 1, &Bytes::from("bbbb"));
+        drop(cache);
+        // left over by an interrupted write
+        fs::write(dir.join("a.v1.4.2.0.tmp"), "cccc").unwrap();
+
+        let cache = DiskCache::new(dir.clone(), 100, 4).unwrap();
+        assert_eq!(cache.get("a", "v1", 0).unwrap(), Bytes::from("aaaa"));
+        assert_eq!(cache.get("a", "v1", 1).unwrap(), Bytes::from("bbbb"));
+        assert!(!dir.join("a.v1.4.2.0.tmp").exists());
+        drop(cache);
+
+        // a smaller limit drops blocks right away
+        let cache = DiskCache::new(dir.clone(), 4, 4).unwrap();
+        assert_eq!(cache.state.lock().entries.len(), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
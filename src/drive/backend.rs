@@ -0,0 +1,231 @@
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, warn};
+
+use super::model::*;
+use super::{DownloadError, UploadError};
+
+const PART_RETRIES: u32 = 3;
+const PART_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Ranges smaller than this aren't worth another connection
+const MIN_RANGE_SIZE: usize = 1024 * 1024;
+
+/// Operations the FUSE layer needs from a PikPak-like drive.
+///
+/// `PikpakDrive` talks to the real PikPak API, `MemoryDrive` (only built for
+/// tests) keeps everything in memory so the VFS can be driven without network
+/// access.
+pub trait DriveBackend: Clone + Send + Sync + 'static {
+    /// List one page of children of `parent_file_id`
+    fn list(&self, parent_file_id: &str, marker: Option<&str>) -> Result<ListFileResponse>;
+
+    /// List all children of `parent_file_id`, following `next_page_token`
+    fn list_all(&self, parent_file_id: &str) -> Result<Vec<PikpakFile>> {
+        debug!("drive list_all: {}", parent_file_id);
+        let mut files = Vec::new();
+        let mut marker = None;
+        loop {
+            let res = self.list(parent_file_id, marker.as_deref())?;
+            files.extend(res.files);
+            if res.next_page_token.is_empty() {
+                break;
+            }
+            marker = Some(res.next_page_token);
+        }
+        Ok(files)
+    }
+
+    fn create_folder(&self, parent_id: &str, folder_name: &str) -> Result<CreateFolderResponse>;
+
+    fn remove_file(&self, file_id: &str) -> Result<TaskResponse>;
+
+    /// Move a file to the trash, it can still be restored from there
+    fn trash_file(&self, file_id: &str) -> Result<TaskResponse>;
+
+    /// Restore a file from the trash
+    fn untrash_file(&self, file_id: &str) -> Result<TaskResponse>;
+
+    fn move_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse>;
+
+    fn rename_file(&self, file_id: &str, new_name: &str) -> Result<PikpakFile>;
+
+    fn copy_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse>;
+
+    /// Create a file entry and get the OSS credentials to upload its content
+    fn create_file_with_proof(
+        &self,
+        name: &str,
+        parent_file_id: &str,
+        hash: &str,
+        size: u64,
+    ) -> Result<UploadResponse>;
+
+    /// Initiate an OSS multipart upload, returns the upload id
+    fn get_pre_upload_info(&self, oss_args: &OssArgs) -> Result<String>;
+
+    fn upload_chunk(
+        &self,
+        file: &PikpakFile,
+        oss_args: &OssArgs,
+        upload_id: &str,
+        current_chunk: u64,
+        body: Bytes,
+    ) -> Result<PartInfo>;
+
+    /// Upload the parts `part_numbers` with up to `concurrency` parts in
+    /// flight, `read_part` provides the data of a part and `on_part` is called
+    /// with every uploaded part. A failed part is retried on its own before
+    /// giving up.
+    #[allow(clippy::too_many_arguments)]
+    fn upload_parts<R, P>(
+        &self,
+        file: &PikpakFile,
+        oss_args: &OssArgs,
+        upload_id: &str,
+        part_numbers: &[u64],
+        concurrency: usize,
+        read_part: R,
+        on_part: P,
+    ) -> Result<()>
+    where
+        R: Fn(u64) -> Result<Bytes> + Sync,
+        P: Fn(PartInfo) + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let upload = || -> Result<()> {
+            while !failed.load(Ordering::SeqCst) {
+                let part_number = match part_numbers.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    Some(part_number) => *part_number,
+                    None => return Ok(()),
+                };
+                let body = match read_part(part_number) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        failed.store(true, Ordering::SeqCst);
+                        return Err(err);
+                    }
+                };
+                let mut attempt = 0;
+                let part = loop {
+                    match self.upload_chunk(file, oss_args, upload_id, part_number, body.clone()) {
+                        Ok(part) => break part,
+                        Err(err) if attempt < PART_RETRIES && is_retryable(&err) => {
+                            attempt += 1;
+                            warn!(part_number = part_number, attempt = attempt, error = %err, "upload part failed, retrying");
+                            thread::sleep(PART_RETRY_DELAY * (1 << (attempt - 1)));
+                        }
+                        Err(err) => {
+                            failed.store(true, Ordering::SeqCst);
+                            return Err(err);
+                        }
+                    }
+                };
+                on_part(part);
+            }
+            Ok(())
+        };
+        thread::scope(|scope| {
+            let workers: Vec<_> = (1..concurrency.min(part_numbers.len()))
+                .map(|_| scope.spawn(upload))
+                .collect();
+            let mut res = upload();
+            for worker in workers {
+                let worker_res = worker.join().unwrap_or_else(|_| Err(anyhow!("upload worker panicked")));
+                res = res.and(worker_res);
+            }
+            res
+        })
+    }
+
+    fn complete_upload(
+        &self,
+        file: &PikpakFile,
+        upload_tags: String,
+        oss_args: &OssArgs,
+        upload_id: &str,
+    ) -> Result<()>;
+
+    /// Abort an OSS multipart upload, dropping the parts uploaded so far
+    fn abort_upload(&self, oss_args: &OssArgs, upload_id: &str) -> Result<()>;
+
+    fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes>;
+
+    /// Download `size` bytes from `start_pos` split into up to `connections`
+    /// ranged requests running in parallel, stitched back in order
+    fn download_ranges(
+        &self,
+        url: &str,
+        start_pos: u64,
+        size: usize,
+        connections: usize,
+    ) -> Result<Bytes> {
+        let connections = connections.min(size / MIN_RANGE_SIZE).max(1);
+        if connections == 1 {
+            return self.download(url, start_pos, size);
+        }
+        let range_size = size.div_ceil(connections);
+        let ranges: Vec<(u64, usize)> = (0..size)
+            .step_by(range_size)
+            .map(|offset| (start_pos + offset as u64, range_size.min(size - offset)))
+            .collect();
+        debug!(url = %url, start = start_pos, size = size, ranges = ranges.len(), "download ranges");
+        let parts: Vec<Result<Bytes>> = thread::scope(|scope| {
+            let workers: Vec<_> = ranges[1..]
+                .iter()
+                .map(|&(pos, len)| scope.spawn(move || self.download(url, pos, len)))
+                .collect();
+            let first = self.download(url, ranges[0].0, ranges[0].1);
+            iter::once(first)
+                .chain(workers.into_iter().map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("download worker panicked")))
+                }))
+                .collect()
+        });
+        let mut data = BytesMut::with_capacity(size);
+        for ((_, len), part) in ranges.iter().zip(parts) {
+            let part = match part {
+                Ok(part) => part,
+                Err(err) => {
+                    // report everything received in order so the download can
+                    // resume after it
+                    if let Some(DownloadError::Interrupted { received, .. }) = err.downcast_ref() {
+                        data.extend_from_slice(received);
+                    }
+                    if data.is_empty() {
+                        return Err(err);
+                    }
+                    return Err(DownloadError::Interrupted {
+                        received: data.freeze(),
+                        reason: err.to_string(),
+                    }
+                    .into());
+                }
+            };
+            data.extend_from_slice(&part);
+            // nothing after a short range is contiguous
+            if part.len() < *len {
+                break;
+            }
+        }
+        Ok(data.freeze())
+    }
+
+    fn get_download_url(&self, file_id: &str) -> Result<DownloadUrl>;
+
+    /// Returns `(used_size, total_size)` in bytes
+    fn get_quota(&self) -> Result<(u64, u64)>;
+}
+
+fn is_retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<UploadError>()
+        .map(UploadError::is_retryable)
+        .unwrap_or(true)
+}
@@ -0,0 +1,800 @@
+//! Local stand-in for the PikPak API and Aliyun OSS
+//!
+//! Serves the endpoints `PikpakDrive` talks to on `127.0.0.1`, backed by a
+//! `MemoryDrive`, so the whole HTTP path including multipart uploads can be
+//! exercised without network access.
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{debug, error};
+use url::form_urlencoded;
+
+use super::backend::DriveBackend;
+use super::memory::MemoryDrive;
+use super::model::*;
+use super::{DriveConfig, RateSchedule, UploadError};
+
+pub const ACCESS_TOKEN: &str = "local-access-token";
+
+const API_PREFIX: &str = "/drive/v1/files";
+const ABOUT_PATH: &str = "/drive/v1/about";
+const SIGNIN_PATH: &str = "/v1/auth/signin";
+const OSS_PREFIX: &str = "/oss/";
+const DOWNLOAD_PREFIX: &str = "/download/";
+const MEMORY_URL_SCHEME: &str = "memory://";
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    // send only the first half of the body, then drop the connection
+    cut: bool,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            cut: false,
+        }
+    }
+
+    fn json<T: Serialize>(value: &T) -> Self {
+        let mut res = Self::new(200);
+        res.headers.push(("Content-Type", "application/json".to_string()));
+        res.body = serde_json::to_vec(value).unwrap_or_default();
+        res
+    }
+
+    fn xml(body: String) -> Self {
+        let mut res = Self::new(200);
+        res.headers.push(("Content-Type", "application/xml".to_string()));
+        res.body = body.into_bytes();
+        res
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        let mut res = Self::json(&serde_json::json!({
+            "error": "local_server_error",
+            "error_description": message,
+        }));
+        res.status = status;
+        res
+    }
+}
+
+/// In-process HTTP server emulating PikPak, bound to `127.0.0.1`
+pub struct LocalServer {
+    addr: SocketAddr,
+    drive: MemoryDrive,
+    shutdown: Arc<AtomicBool>,
+    link_epoch: Arc<AtomicU64>,
+    cut_downloads: Arc<AtomicUsize>,
+}
+
+impl LocalServer {
+    pub fn start(drive: MemoryDrive) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let link_epoch = Arc::new(AtomicU64::new(0));
+        let cut_downloads = Arc::new(AtomicUsize::new(0));
+        let handler = Handler {
+            base_url: format!("http://{}", addr),
+            drive: drive.clone(),
+            link_epoch: link_epoch.clone(),
+            cut_downloads: cut_downloads.clone(),
+        };
+        let stop = shutdown.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let handler = handler.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = handler.serve(stream) {
+                                debug!(error = %err, "local server: connection closed");
+                            }
+                        });
+                    }
+                    Err(err) => error!(error = %err, "local server: accept failed"),
+                }
+            }
+        });
+        debug!(addr = %addr, "local server: started");
+        Ok(Self {
+            addr,
+            drive,
+            shutdown,
+            link_epoch,
+            cut_downloads,
+        })
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// The drive backing the server, use it to seed and inspect content
+    pub fn drive(&self) -> &MemoryDrive {
+        &self.drive
+    }
+
+    /// Expire the download links handed out so far, downloads with them
+    /// fail with 410 like expired CDN links
+    pub fn expire_links(&self) {
+        self.link_epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Break off the next `times` downloads halfway through the body
+    pub fn cut_downloads(&self, times: usize) {
+        self.cut_downloads.store(times, Ordering::SeqCst);
+    }
+
+    /// A `DriveConfig` pointing every endpoint at this server
+    pub fn drive_config(&self, workdir: &Path) -> DriveConfig {
+        DriveConfig {
+            api_base_url: format!("{}{}", self.base_url(), API_PREFIX),
+            refresh_token_url: format!("{}{}", self.base_url(), SIGNIN_PATH),
+            workdir: Some(workdir.to_path_buf()),
+            api_host: None,
+            oss_endpoint: Some(format!("{}{}", self.base_url(), OSS_PREFIX.trim_end_matches('/'))),
+            max_download_rate: RateSchedule::default(),
+            max_upload_rate: RateSchedule::default(),
+        }
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the accept loop
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+#[derive(Clone)]
+struct Handler {
+    base_url: String,
+    drive: MemoryDrive,
+    // bumped to expire the download links handed out so far
+    link_epoch: Arc<AtomicU64>,
+    // number of upcoming downloads to break off
+    cut_downloads: Arc<AtomicUsize>,
+}
+
+impl Handler {
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        while let Some(req) = read_request(&mut reader)? {
+            debug!(method = %req.method, path = %req.path, "local server: request");
+            let res = match self.route(&req) {
+                Ok(res) => res,
+                Err(err) => match err.downcast_ref::<UploadError>() {
+                    Some(UploadError::Oss { status, code, message, request_id }) => {
+                        let mut res = Response::xml(format!(
+                            "<Error><Code>{}</Code><Message>{}</Message><RequestId>{}</RequestId></Error>",
+                            code, message, request_id
+                        ));
+                        res.status = *status;
+                        res
+                    }
+                    _ => Response::error(400, &err.to_string()),
+                },
+            };
+            write_response(&mut writer, &res)?;
+            if res.cut {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn route(&self, req: &Request) -> Result<Response> {
+        let path = req.path.as_str();
+        if path == SIGNIN_PATH && req.method == "POST" {
+            return self.signin(req);
+        }
+        if let Some(file_id) = path.strip_prefix(DOWNLOAD_PREFIX) {
+            return self.download(req, file_id);
+        }
+        if let Some(key) = path.strip_prefix(OSS_PREFIX) {
+            if !authorized(req, "OSS ") {
+                return Ok(Response::error(403, "missing OSS signature"));
+            }
+            return self.oss(req, key);
+        }
+        if path == ABOUT_PATH && req.method == "GET" {
+            if !authorized(req, &format!("Bearer {}", ACCESS_TOKEN)) {
+                return Ok(Response::error(401, "unauthorized"));
+            }
+            let (used_size, total_size) = self.drive.get_quota()?;
+            return Ok(Response::json(&AboutResponse {
+                quota: Quota {
+                    limit: total_size.to_string(),
+                    usage: used_size.to_string(),
+                },
+            }));
+        }
+        if let Some(rest) = path.strip_prefix(API_PREFIX) {
+            if !authorized(req, &format!("Bearer {}", ACCESS_TOKEN)) {
+                return Ok(Response::error(401, "unauthorized"));
+            }
+            return self.api(req, rest);
+        }
+        Ok(Response::error(404, "not found"))
+    }
+
+    fn signin(&self, req: &Request) -> Result<Response> {
+        let body: Value = serde_json::from_slice(&req.body)?;
+        let username = body["username"].as_str().unwrap_or_default();
+        if username.is_empty() {
+            return Ok(Response::error(400, "missing username"));
+        }
+        Ok(Response::json(&serde_json::json!({
+            "access_token": ACCESS_TOKEN,
+            "refresh_token": "local-refresh-token",
+            "expires_in": 7200,
+            "token_type": "Bearer",
+        })))
+    }
+
+    fn api(&self, req: &Request, rest: &str) -> Result<Response> {
+        let body = || -> Result<Value> { Ok(serde_json::from_slice(&req.body)?) };
+        let ids = |body: &Value| -> Vec<String> {
+            body["ids"]
+                .as_array()
+                .map(|ids| {
+                    ids.iter()
+                        .filter_map(|id| id.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        match (req.method.as_str(), rest) {
+            ("GET", "") => {
+                let parent_id = req.query.get("parent_id").map(String::as_str).unwrap_or("");
+                let marker = req.query.get("page_token").map(String::as_str);
+                let mut res = self.drive.list(parent_id, marker)?;
+                for file in res.files.iter_mut() {
+                    self.rewrite_link(file);
+                }
+                Ok(Response::json(&serde_json::json!({
+                    "kind": res.kind,
+                    "next_page_token": res.next_page_token,
+                    "files": res.files,
+                })))
+            }
+            ("POST", "") => {
+                let body = body()?;
+                let name = body["name"].as_str().context("missing name")?;
+                let parent_id = body["parent_id"].as_str().unwrap_or("");
+                if body["kind"] == "drive#folder" {
+                    let mut res = self.drive.create_folder(parent_id, name)?;
+                    self.rewrite_link(&mut res.file);
+                    Ok(Response::json(&res))
+                } else {
+                    let hash = body["hash"].as_str().unwrap_or("");
+                    let size = body["size"].as_u64().unwrap_or(0);
+                    let mut res = self.drive.create_file_with_proof(name, parent_id, hash, size)?;
+                    if let Some(resumable) = res.resumable.as_mut() {
+                        resumable.params.endpoint = self.base_url.trim_start_matches("http://").to_string();
+                    }
+                    self.rewrite_link(&mut res.file);
+                    Ok(Response::json(&res))
+                }
+            }
+            ("POST", ":batchDelete") => {
+                for id in ids(&body()?) {
+                    self.drive.remove_file(&id)?;
+                }
+                Ok(Response::json(&TaskResponse { task_id: String::new() }))
+            }
+            ("POST", ":batchTrash") => {
+                for id in ids(&body()?) {
+                    self.drive.trash_file(&id)?;
+                }
+                Ok(Response::json(&TaskResponse { task_id: String::new() }))
+            }
+            ("POST", ":batchUntrash") => {
+                for id in ids(&body()?) {
+                    self.drive.untrash_file(&id)?;
+                }
+                Ok(Response::json(&TaskResponse { task_id: String::new() }))
+            }
+            ("POST", ":batchMove") | ("POST", ":batchCopy") => {
+                let body = body()?;
+                let to = body["to"]["parent_id"].as_str().unwrap_or("");
+                for id in ids(&body) {
+                    if rest == ":batchMove" {
+                        self.drive.move_file(&id, to)?;
+                    } else {
+                        self.drive.copy_file(&id, to)?;
+                    }
+                }
+                Ok(Response::json(&TaskResponse { task_id: String::new() }))
+            }
+            ("GET", file_id) => {
+                let file_id = file_id.trim_start_matches('/');
+                match self.drive.get(file_id) {
+                    Some(mut file) => {
+                        self.rewrite_link(&mut file);
+                        Ok(Response::json(&file))
+                    }
+                    None => Ok(Response::error(404, "file not found")),
+                }
+            }
+            ("PATCH", file_id) => {
+                let name = body()?["name"].as_str().context("missing name")?.to_string();
+                let mut file = self.drive.rename_file(file_id.trim_start_matches('/'), &name)?;
+                self.rewrite_link(&mut file);
+                Ok(Response::json(&file))
+            }
+            _ => Ok(Response::error(404, "not found")),
+        }
+    }
+
+    fn oss(&self, req: &Request, key: &str) -> Result<Response> {
+        let oss_args = OssArgs {
+            bucket: "memory".to_string(),
+            endpoint: self.base_url.clone(),
+            access_key_id: String::new(),
+            access_key_secret: String::new(),
+            key: key.to_string(),
+            security_token: String::new(),
+        };
+        let file = self.drive.get(key).context("object not found")?;
+        match req.method.as_str() {
+            "POST" if req.query.contains_key("uploads") => {
+                let upload_id = self.drive.get_pre_upload_info(&oss_args)?;
+                Ok(Response::xml(format!(
+                    "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    oss_args.bucket, key, upload_id
+                )))
+            }
+            "PUT" => {
+                let upload_id = req.query.get("uploadId").context("missing uploadId")?;
+                let part_number = req
+                    .query
+                    .get("partNumber")
+                    .context("missing partNumber")?
+                    .parse()?;
+                let body = Bytes::copy_from_slice(&req.body);
+                let part = self
+                    .drive
+                    .upload_chunk(&file, &oss_args, upload_id, part_number, body)?;
+                let mut res = Response::new(200);
+                res.headers.push(("ETag", part.ETag));
+                Ok(res)
+            }
+            "DELETE" => {
+                let upload_id = req.query.get("uploadId").context("missing uploadId")?;
+                self.drive.abort_upload(&oss_args, upload_id)?;
+                Ok(Response::new(204))
+            }
+            "POST" => {
+                let upload_id = req.query.get("uploadId").context("missing uploadId")?;
+                let upload_tags = String::from_utf8_lossy(&req.body).to_string();
+                self.drive
+                    .complete_upload(&file, upload_tags, &oss_args, upload_id)?;
+                Ok(Response::xml(format!(
+                    "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key></CompleteMultipartUploadResult>",
+                    oss_args.bucket, key
+                )))
+            }
+            _ => Ok(Response::error(405, "method not allowed")),
+        }
+    }
+
+    fn download(&self, req: &Request, file_id: &str) -> Result<Response> {
+        // the epoch stands in for the signature of a CDN link
+        match req.query.get("epoch").map(|epoch| epoch.parse::<u64>()) {
+            Some(Ok(epoch)) if epoch == self.link_epoch.load(Ordering::SeqCst) => {}
+            Some(Ok(_)) => return Ok(Response::error(410, "link expired")),
+            _ => return Ok(Response::error(403, "missing link signature")),
+        }
+        let content = match self.drive.content(file_id) {
+            Some(content) => content,
+            None => return Ok(Response::error(404, "file not found")),
+        };
+        let (start, end) = match req.headers.get("range").and_then(|r| r.strip_prefix("bytes=")) {
+            Some(range) => {
+                let (start, end) = range.split_once('-').context("invalid range")?;
+                let start: usize = start.parse()?;
+                let end: usize = if end.is_empty() {
+                    content.len().saturating_sub(1)
+                } else {
+                    end.parse()?
+                };
+                (start, std::cmp::min(end + 1, content.len()))
+            }
+            None => (0, content.len()),
+        };
+        let start = std::cmp::min(start, end);
+        let mut res = Response::new(if req.headers.contains_key("range") { 206 } else { 200 });
+        res.headers.push(("Content-Type", "application/octet-stream".to_string()));
+        res.body = content[start..end].to_vec();
+        res.cut = self
+            .cut_downloads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        Ok(res)
+    }
+
+    fn rewrite_link(&self, file: &mut PikpakFile) {
+        if let Some(id) = file.web_content_link.strip_prefix(MEMORY_URL_SCHEME) {
+            file.web_content_link = format!(
+                "{}{}{}?epoch={}",
+                self.base_url,
+                DOWNLOAD_PREFIX,
+                id,
+                self.link_epoch.load(Ordering::SeqCst)
+            );
+        }
+    }
+}
+
+fn authorized(req: &Request, prefix: &str) -> bool {
+    req.headers
+        .get("authorization")
+        .map(|auth| auth.starts_with(prefix))
+        .unwrap_or(false)
+}
+
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let content_length = headers
+        .get("content-length")
+        .and_then(|len| len.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target.as_str(), ""));
+    let query = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    Ok(Some(Request {
+        method,
+        path: path.to_string(),
+        query,
+        headers,
+        body,
+    }))
+}
+
+fn write_response(writer: &mut impl Write, res: &Response) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} Local\r\nContent-Length: {}\r\n", res.status, res.body.len());
+    for (name, value) in &res.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes())?;
+    let body = if res.cut {
+        &res.body[..res.body.len() / 2]
+    } else {
+        &res.body[..]
+    };
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::drive::model::Credentials;
+    use crate::drive::{DownloadError, PikpakDrive, PikpakFile};
+    use crate::file_cache::{FileCache, FileCacheConfig};
+    use crate::staging::StagingFile;
+    use crate::upload_queue::{UploadJob, UploadQueue};
+
+    const PART_SIZE: u64 = 1024 * 1024;
+
+    fn workdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pikpak-fuse-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// `PikpakDrive` signed in to `server`
+    fn connect(server: &LocalServer, dir: &Path) -> PikpakDrive {
+        let credentials = Credentials {
+            username: "user".to_string(),
+            password: "password".to_string(),
+        };
+        PikpakDrive::new(server.drive_config(dir), credentials).unwrap()
+    }
+
+    /// Bytes that differ from part to part so misplaced parts are caught
+    fn content(size: usize) -> Vec<u8> {
+        let mut state: u32 = 0x9e37_79b9;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn upload(
+        queue: &UploadQueue<PikpakDrive>,
+        done: &mpsc::Receiver<(UploadJob, PikpakFile)>,
+        dir: &Path,
+        parent_id: &str,
+        name: &str,
+        data: &[u8],
+    ) -> (UploadJob, PikpakFile) {
+        let mut staging = StagingFile::create(dir, &format!("{}.upload", name)).unwrap();
+        staging.write_at(0, data).unwrap();
+        queue.enqueue(staging, 0, name, parent_id, None, PART_SIZE).unwrap();
+        done.recv_timeout(Duration::from_secs(30)).unwrap()
+    }
+
+    #[test]
+    fn pikpak_drive_round_trip() {
+        let dir = workdir("local-server");
+        let server = LocalServer::start(MemoryDrive::new()).unwrap();
+        let drive = connect(&server, &dir);
+        let (tx, done) = mpsc::channel();
+        let tx = parking_lot::Mutex::new(tx);
+        let queue = UploadQueue::new(drive.clone(), dir.join("uploads"), 2, move |job, file| {
+            let _ = tx.lock().send((job.clone(), file.clone()));
+        });
+
+        let folder = drive.create_folder("", "d").unwrap().file;
+        let names = |parent_id: &str| -> Vec<String> {
+            drive.list_all(parent_id).unwrap().into_iter().map(|file| file.name).collect()
+        };
+        assert_eq!(names(""), vec!["d"]);
+
+        // multipart upload, the last part is short
+        let data = content(3 * PART_SIZE as usize + 1234);
+        let staging_dir = dir.join("staging");
+        let (job, file) = upload(&queue, &done, &staging_dir, &folder.id, "a.bin", &data);
+        assert_eq!(job.parts.len(), 4);
+        assert!(job.upload_id.is_some());
+        assert_eq!(names(&folder.id), vec!["a.bin"]);
+        assert_eq!(server.drive().content(&file.id).unwrap(), Bytes::from(data.clone()));
+        let (used, total) = drive.get_quota().unwrap();
+        assert_eq!(used, data.len() as u64);
+        assert!(total > used);
+
+        // the same content again is completed by the server without uploading
+        let (job, copy) = upload(&queue, &done, &staging_dir, &folder.id, "c.bin", &data);
+        assert!(job.upload_id.is_none());
+        assert!(job.parts.is_empty());
+        assert_ne!(copy.id, file.id);
+        assert_eq!(server.drive().content(&copy.id).unwrap(), Bytes::from(data.clone()));
+        drive.remove_file(&copy.id).unwrap();
+
+        // ranged downloads, on one connection and split over several
+        let url = drive.get_download_url(&file.id).unwrap().url;
+        let range = drive.download(&url, PART_SIZE - 10, 20 + PART_SIZE as usize).unwrap();
+        assert_eq!(&range[..], &data[PART_SIZE as usize - 10..2 * PART_SIZE as usize + 10]);
+        let whole = drive.download_ranges(&url, 0, data.len(), 3).unwrap();
+        assert_eq!(&whole[..], &data[..]);
+        let tail = drive.download_ranges(&url, 100, data.len(), 3).unwrap();
+        assert_eq!(&tail[..], &data[100..]);
+        // a range past the end is cut short
+        let tail = drive.download(&url, data.len() as u64 - 100, 1000).unwrap();
+        assert_eq!(&tail[..], &data[data.len() - 100..]);
+
+        // rename, copy, move and remove
+        let renamed = drive.rename_file(&file.id, "b.bin").unwrap();
+        assert_eq!(renamed.name, "b.bin");
+        drive.copy_file(&file.id, "").unwrap();
+        assert_eq!(names(""), vec!["b.bin", "d"]);
+        drive.move_file(&file.id, "").unwrap();
+        assert_eq!(names(""), vec!["b.bin", "b.bin", "d"]);
+        assert!(names(&folder.id).is_empty());
+        drive.remove_file(&folder.id).unwrap();
+        assert_eq!(names(""), vec!["b.bin", "b.bin"]);
+        // trashed files are kept but not listed
+        drive.trash_file(&file.id).unwrap();
+        assert_eq!(names(""), vec!["b.bin"]);
+        assert!(server.drive().is_trashed(&file.id));
+        drive.untrash_file(&file.id).unwrap();
+        assert_eq!(names(""), vec!["b.bin", "b.bin"]);
+        queue.stop();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn expired_links_are_refreshed() {
+        let dir = workdir("link-expiry");
+        let server = LocalServer::start(MemoryDrive::new()).unwrap();
+        let drive = connect(&server, &dir);
+        let data = content(3000);
+        let file = server.drive().add_file("", "a.bin", data.clone());
+
+        let url = drive.get_download_url(&file.id).unwrap().url;
+        assert_eq!(&drive.download(&url, 0, 10).unwrap()[..], &data[..10]);
+        server.expire_links();
+        let expired = |url: &str| -> Option<u16> {
+            match drive.download(url, 0, 10).unwrap_err().downcast_ref::<DownloadError>() {
+                Some(DownloadError::LinkExpired(status)) => Some(*status),
+                _ => None,
+            }
+        };
+        assert_eq!(expired(&url), Some(410));
+        let (unsigned, _) = url.split_once('?').unwrap();
+        assert_eq!(expired(unsigned), Some(403));
+
+        // the file cache gets a new link and carries on
+        let config = FileCacheConfig {
+            read_buffer_size: 1024,
+            read_ahead: 0,
+            url_cache_size: 10,
+            url_cache_ttl: 60,
+            memory_cache_size: 1024 * 1024,
+            download_connections: 1,
+            download_retries: 0,
+            short_reads: false,
+        };
+        let cache = FileCache::new(drive.clone(), config, None);
+        cache.open(1, file.id.clone(), data.len() as u64, file.content_version());
+        assert_eq!(&cache.read(1, 0, 1024).unwrap()[..], &data[..1024]);
+        server.expire_links();
+        assert_eq!(&cache.read(1, 1024, 2000).unwrap()[..], &data[1024..]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn interrupted_downloads_resume() {
+        let dir = workdir("download-resume");
+        let server = LocalServer::start(MemoryDrive::new()).unwrap();
+        let drive = connect(&server, &dir);
+        let data = content(3000);
+        let file = server.drive().add_file("", "a.bin", data.clone());
+
+        // what arrived before the connection broke off is kept
+        let url = drive.get_download_url(&file.id).unwrap().url;
+        server.cut_downloads(1);
+        let err = drive.download(&url, 0, data.len()).unwrap_err();
+        match err.downcast_ref::<DownloadError>() {
+            Some(err @ DownloadError::Interrupted { received, .. }) => {
+                assert_eq!(&received[..], &data[..1500]);
+                assert!(err.is_retryable());
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+
+        let cache = |download_retries, short_reads| {
+            let config = FileCacheConfig {
+                read_buffer_size: 4096,
+                read_ahead: 0,
+                url_cache_size: 10,
+                url_cache_ttl: 60,
+                memory_cache_size: 1024 * 1024,
+                download_connections: 1,
+                download_retries,
+                short_reads,
+            };
+            let cache = FileCache::new(drive.clone(), config, None);
+            cache.open(1, file.id.clone(), data.len() as u64, file.content_version());
+            cache
+        };
+        // retries pick up after the bytes received
+        server.cut_downloads(2);
+        assert_eq!(&cache(2, false).read(1, 0, 3000).unwrap()[..], &data[..]);
+        server.cut_downloads(2);
+        assert_eq!(&cache(1, true).read(1, 0, 3000).unwrap()[..], &data[..2250]);
+        server.cut_downloads(1);
+        assert!(cache(0, false).read(1, 0, 3000).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn aborted_upload_is_rejected() {
+        let dir = workdir("upload-abort");
+        let server = LocalServer::start(MemoryDrive::new()).unwrap();
+        let drive = connect(&server, &dir);
+
+        let res = drive.create_file_with_proof("a.bin", "", "hash", 10).unwrap();
+        let params = &res.resumable.as_ref().unwrap().params;
+        let oss_args = OssArgs {
+            bucket: params.bucket.to_string(),
+            key: params.key.to_string(),
+            endpoint: params.endpoint.to_string(),
+            access_key_id: params.access_key_id.to_string(),
+            access_key_secret: params.access_key_secret.to_string(),
+            security_token: params.security_token.to_string(),
+        };
+        let part = Bytes::from_static(b"0123456789");
+
+        // the OSS error document is parsed
+        let err = drive
+            .upload_chunk(&res.file, &oss_args, "U-gone", 1, part.clone())
+            .unwrap_err();
+        match err.downcast_ref::<UploadError>() {
+            Some(err @ UploadError::Oss { status: 404, code, .. }) => {
+                assert_eq!(code, "NoSuchUpload");
+                assert!(!err.is_retryable());
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+
+        let upload_id = drive.get_pre_upload_info(&oss_args).unwrap();
+        drive
+            .upload_chunk(&res.file, &oss_args, &upload_id, 1, part)
+            .unwrap();
+        drive.abort_upload(&oss_args, &upload_id).unwrap();
+        // the pending file goes with its upload
+        assert!(server.drive().get(&res.file.id).is_none());
+        assert!(drive.abort_upload(&oss_args, &upload_id).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn create_file_with_proof_reports_api_errors() {
+        let dir = workdir("create-file-error");
+        let server = LocalServer::start(MemoryDrive::new()).unwrap();
+        let drive = connect(&server, &dir);
+
+        // the JSON error body isn't taken for an upload response
+        let err = drive
+            .create_file_with_proof("a.bin", "missing", "hash", 10)
+            .unwrap_err();
+        match err.downcast_ref::<UploadError>() {
+            Some(err @ UploadError::Status(400)) => assert!(!err.is_retryable()),
+            _ => panic!("unexpected error {:?}", err),
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,508 @@
+//! In-memory drive backend
+//!
+//! Mirrors the behaviour of the PikPak API closely enough to run the VFS
+//! deterministically without network access.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use parking_lot::RwLock;
+
+use super::backend::DriveBackend;
+use super::model::*;
+use super::UploadError;
+use crate::gcid::GcidHasher;
+
+const URL_SCHEME: &str = "memory://";
+const DEFAULT_PAGE_SIZE: usize = 100;
+const DEFAULT_TOTAL_SIZE: u64 = 6 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct PendingUpload {
+    file_id: String,
+    parts: BTreeMap<u64, Bytes>,
+}
+
+#[derive(Debug)]
+struct State {
+    files: HashMap<String, PikpakFile>,
+    contents: HashMap<String, Bytes>,
+    uploads: HashMap<String, PendingUpload>,
+    // ids of trashed files, they're kept but not listed
+    trash: HashSet<String>,
+    // method name -> number of upcoming calls that fail
+    failures: HashMap<&'static str, usize>,
+    // method name -> number of upcoming calls that panic
+    panics: HashMap<&'static str, usize>,
+    next_id: u64,
+    page_size: usize,
+    total_size: u64,
+}
+
+impl State {
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{:08}", prefix, self.next_id)
+    }
+
+    fn insert_file(&mut self, kind: &str, parent_id: &str, name: &str, phase: &str) -> PikpakFile {
+        let id = self.next_id("M");
+        let now = DateTime::new(SystemTime::now());
+        let file = PikpakFile {
+            kind: kind.to_string(),
+            id: id.clone(),
+            parent_id: parent_id.to_string(),
+            phase: phase.to_string(),
+            name: name.to_string(),
+            size: "0".to_string(),
+            file_extension: "".to_string(),
+            mime_type: "".to_string(),
+            web_content_link: format!("{}{}", URL_SCHEME, id),
+            created_time: now.clone(),
+            modified_time: now,
+            medias: Vec::new(),
+            hash: None,
+        };
+        self.files.insert(id, file.clone());
+        file
+    }
+
+    fn set_content(&mut self, file_id: &str, content: Bytes) {
+        if let Some(file) = self.files.get_mut(file_id) {
+            file.size = content.len().to_string();
+            file.hash = Some(content_hash(&content));
+            file.phase = "PHASE_TYPE_COMPLETE".to_string();
+            file.modified_time = DateTime::new(SystemTime::now());
+        }
+        self.contents.insert(file_id.to_string(), content);
+    }
+
+    fn children(&self, parent_id: &str) -> Vec<&PikpakFile> {
+        let mut children: Vec<_> = self
+            .files
+            .values()
+            .filter(|f| f.parent_id == parent_id && f.phase == "PHASE_TYPE_COMPLETE")
+            .filter(|f| !self.trash.contains(&f.id))
+            .collect();
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        children
+    }
+
+    fn remove_tree(&mut self, file_id: &str) {
+        let children: Vec<String> = self
+            .files
+            .values()
+            .filter(|f| f.parent_id == file_id)
+            .map(|f| f.id.clone())
+            .collect();
+        for child in children {
+            self.remove_tree(&child);
+        }
+        self.files.remove(file_id);
+        self.contents.remove(file_id);
+        self.trash.remove(file_id);
+    }
+
+    fn copy_tree(&mut self, file_id: &str, new_parent_id: &str) -> Result<()> {
+        let file = self.files.get(file_id).context("file not found")?.clone();
+        let copy = self.insert_file(&file.kind, new_parent_id, &file.name, &file.phase);
+        if let Some(content) = self.contents.get(file_id).cloned() {
+            self.set_content(&copy.id, content);
+        }
+        let children: Vec<String> = self
+            .files
+            .values()
+            .filter(|f| f.parent_id == file_id)
+            .map(|f| f.id.clone())
+            .collect();
+        for child in children {
+            self.copy_tree(&child, &copy.id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Count down an injected failure of `method`, whether this call is hit
+fn take(counts: &mut HashMap<&'static str, usize>, method: &str) -> bool {
+    match counts.get_mut(method) {
+        Some(times) if *times > 0 => {
+            if *times != usize::MAX {
+                *times -= 1;
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// A `DriveBackend` keeping the whole drive in memory
+#[derive(Debug, Clone)]
+pub struct MemoryDrive {
+    state: Arc<RwLock<State>>,
+}
+
+impl Default for MemoryDrive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDrive {
+    pub fn new() -> Self {
+        let state = State {
+            files: HashMap::new(),
+            contents: HashMap::new(),
+            uploads: HashMap::new(),
+            trash: HashSet::new(),
+            failures: HashMap::new(),
+            panics: HashMap::new(),
+            next_id: 0,
+            page_size: DEFAULT_PAGE_SIZE,
+            total_size: DEFAULT_TOTAL_SIZE,
+        };
+        Self {
+            state: Arc::new(RwLock::new(state)),
+        }
+    }
+
+    /// Number of entries returned per `list` page, lower it to exercise paging
+    pub fn with_page_size(self, page_size: usize) -> Self {
+        self.state.write().page_size = page_size.max(1);
+        self
+    }
+
+    /// Total capacity reported by `get_quota`
+    pub fn with_total_size(self, total_size: u64) -> Self {
+        self.state.write().total_size = total_size;
+        self
+    }
+
+    /// Create a folder, the root folder id is the empty string
+    pub fn add_folder(&self, parent_id: &str, name: &str) -> PikpakFile {
+        self.state
+            .write()
+            .insert_file("drive#folder", parent_id, name, "PHASE_TYPE_COMPLETE")
+    }
+
+    /// Create a file with the given content
+    pub fn add_file(&self, parent_id: &str, name: &str, content: impl Into<Bytes>) -> PikpakFile {
+        let mut state = self.state.write();
+        let file = state.insert_file("drive#file", parent_id, name, "PHASE_TYPE_COMPLETE");
+        state.set_content(&file.id, content.into());
+        state.files[&file.id].clone()
+    }
+
+    /// Look up a completed child of `parent_id` by name
+    pub fn find(&self, parent_id: &str, name: &str) -> Option<PikpakFile> {
+        let state = self.state.read();
+        state
+            .children(parent_id)
+            .into_iter()
+            .find(|f| f.name == name)
+            .cloned()
+    }
+
+    pub fn get(&self, file_id: &str) -> Option<PikpakFile> {
+        self.state.read().files.get(file_id).cloned()
+    }
+
+    pub fn is_trashed(&self, file_id: &str) -> bool {
+        self.state.read().trash.contains(file_id)
+    }
+
+    pub fn content(&self, file_id: &str) -> Option<Bytes> {
+        self.state.read().contents.get(file_id).cloned()
+    }
+
+    /// Make the next `times` calls of the `DriveBackend` method `method` fail,
+    /// `usize::MAX` keeps failing until reset with 0
+    pub fn fail(&self, method: &'static str, times: usize) {
+        self.state.write().failures.insert(method, times);
+    }
+
+    /// Make the next `times` calls of the `DriveBackend` method `method`
+    /// panic, like `fail`
+    pub fn panic(&self, method: &'static str, times: usize) {
+        self.state.write().panics.insert(method, times);
+    }
+
+    fn check_failure(&self, method: &'static str) -> Result<()> {
+        let mut state = self.state.write();
+        if take(&mut state.panics, method) {
+            drop(state);
+            panic!("{} panicked", method);
+        }
+        if take(&mut state.failures, method) {
+            bail!("{} failed", method);
+        }
+        Ok(())
+    }
+}
+
+impl DriveBackend for MemoryDrive {
+    fn list(&self, parent_file_id: &str, marker: Option<&str>) -> Result<ListFileResponse> {
+        self.check_failure("list")?;
+        let state = self.state.read();
+        if !parent_file_id.is_empty() && !state.files.contains_key(parent_file_id) {
+            bail!("parent {} not found", parent_file_id);
+        }
+        let start = match marker {
+            Some(marker) if !marker.is_empty() => marker.parse::<usize>()?,
+            _ => 0,
+        };
+        let children = state.children(parent_file_id);
+        let end = std::cmp::min(start + state.page_size, children.len());
+        let next_page_token = if end < children.len() {
+            end.to_string()
+        } else {
+            String::new()
+        };
+        let files = children
+            .get(start..end)
+            .unwrap_or_default()
+            .iter()
+            .map(|f| (*f).clone())
+            .collect();
+        Ok(ListFileResponse {
+            kind: "drive#fileList".to_string(),
+            next_page_token,
+            files,
+        })
+    }
+
+    fn create_folder(&self, parent_id: &str, folder_name: &str) -> Result<CreateFolderResponse> {
+        self.check_failure("create_folder")?;
+        let file = self.add_folder(parent_id, folder_name);
+        Ok(CreateFolderResponse {
+            upload_type: "UPLOAD_TYPE_UNKNOWN".to_string(),
+            file,
+        })
+    }
+
+    fn remove_file(&self, file_id: &str) -> Result<TaskResponse> {
+        self.check_failure("remove_file")?;
+        let mut state = self.state.write();
+        if !state.files.contains_key(file_id) {
+            bail!("file {} not found", file_id);
+        }
+        state.remove_tree(file_id);
+        Ok(TaskResponse {
+            task_id: String::new(),
+        })
+    }
+
+    fn trash_file(&self, file_id: &str) -> Result<TaskResponse> {
+        self.check_failure("trash_file")?;
+        let mut state = self.state.write();
+        if !state.files.contains_key(file_id) {
+            bail!("file {} not found", file_id);
+        }
+        state.trash.insert(file_id.to_string());
+        Ok(TaskResponse {
+            task_id: String::new(),
+        })
+    }
+
+    fn untrash_file(&self, file_id: &str) -> Result<TaskResponse> {
+        self.check_failure("untrash_file")?;
+        let mut state = self.state.write();
+        if !state.trash.remove(file_id) {
+            bail!("file {} not in trash", file_id);
+        }
+        Ok(TaskResponse {
+            task_id: String::new(),
+        })
+    }
+
+    fn move_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse> {
+        self.check_failure("move_file")?;
+        let mut state = self.state.write();
+        let file = state.files.get_mut(file_id).context("file not found")?;
+        file.parent_id = new_parent_id.to_string();
+        Ok(TaskResponse {
+            task_id: String::new(),
+        })
+    }
+
+    fn rename_file(&self, file_id: &str, new_name: &str) -> Result<PikpakFile> {
+        self.check_failure("rename_file")?;
+        let mut state = self.state.write();
+        let file = state.files.get_mut(file_id).context("file not found")?;
+        file.name = new_name.to_string();
+        Ok(file.clone())
+    }
+
+    fn copy_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse> {
+        self.check_failure("copy_file")?;
+        self.state.write().copy_tree(file_id, new_parent_id)?;
+        Ok(TaskResponse {
+            task_id: String::new(),
+        })
+    }
+
+    fn create_file_with_proof(
+        &self,
+        name: &str,
+        parent_file_id: &str,
+        hash: &str,
+        size: u64,
+    ) -> Result<UploadResponse> {
+        self.check_failure("create_file_with_proof")?;
+        let mut state = self.state.write();
+        if !parent_file_id.is_empty() && !state.files.contains_key(parent_file_id) {
+            bail!("parent {} not found", parent_file_id);
+        }
+        let existing = state
+            .files
+            .values()
+            .find(|f| f.hash.as_deref() == Some(hash) && f.size == size.to_string())
+            .and_then(|f| state.contents.get(&f.id).cloned());
+        if let Some(content) = existing {
+            let file = state.insert_file("drive#file", parent_file_id, name, "PHASE_TYPE_PENDING");
+            state.set_content(&file.id, content);
+            let file = state.files[&file.id].clone();
+            return Ok(UploadResponse {
+                upload_type: "UPLOAD_TYPE_UNKNOWN".to_string(),
+                resumable: None,
+                file,
+            });
+        }
+        let mut file = state.insert_file("drive#file", parent_file_id, name, "PHASE_TYPE_PENDING");
+        file.size = size.to_string();
+        state.files.insert(file.id.clone(), file.clone());
+        let params = UploadParams {
+            access_key_id: "memory".to_string(),
+            access_key_secret: "memory".to_string(),
+            bucket: "memory".to_string(),
+            endpoint: "memory".to_string(),
+            expiration: "".to_string(),
+            key: file.id.clone(),
+            security_token: "memory".to_string(),
+        };
+        Ok(UploadResponse {
+            upload_type: "UPLOAD_TYPE_RESUMABLE".to_string(),
+            resumable: Some(Resumable {
+                kind: "drive#resumable".to_string(),
+                provider: "PROVIDER_ALIYUN".to_string(),
+                params,
+            }),
+            file,
+        })
+    }
+
+    fn get_pre_upload_info(&self, oss_args: &OssArgs) -> Result<String> {
+        self.check_failure("get_pre_upload_info")?;
+        let mut state = self.state.write();
+        if !state.files.contains_key(&oss_args.key) {
+            bail!("object {} not found", oss_args.key);
+        }
+        let upload_id = state.next_id("U");
+        state.uploads.insert(
+            upload_id.clone(),
+            PendingUpload {
+                file_id: oss_args.key.clone(),
+                parts: BTreeMap::new(),
+            },
+        );
+        Ok(upload_id)
+    }
+
+    fn upload_chunk(
+        &self,
+        _file: &PikpakFile,
+        _oss_args: &OssArgs,
+        upload_id: &str,
+        current_chunk: u64,
+        body: Bytes,
+    ) -> Result<PartInfo> {
+        self.check_failure("upload_chunk")?;
+        let mut state = self.state.write();
+        let upload = state.uploads.get_mut(upload_id).ok_or_else(|| no_such_upload(upload_id))?;
+        let etag = format!("\"{}\"", content_hash(&body));
+        upload.parts.insert(current_chunk, body);
+        Ok(PartInfo {
+            PartNumber: PartNumber {
+                PartNumber: current_chunk,
+            },
+            ETag: etag,
+        })
+    }
+
+    fn complete_upload(
+        &self,
+        _file: &PikpakFile,
+        _upload_tags: String,
+        _oss_args: &OssArgs,
+        upload_id: &str,
+    ) -> Result<()> {
+        self.check_failure("complete_upload")?;
+        let mut state = self.state.write();
+        let upload = state.uploads.remove(upload_id).ok_or_else(|| no_such_upload(upload_id))?;
+        let mut content = BytesMut::new();
+        for part in upload.parts.values() {
+            content.extend_from_slice(part);
+        }
+        state.set_content(&upload.file_id, content.freeze());
+        Ok(())
+    }
+
+    fn abort_upload(&self, _oss_args: &OssArgs, upload_id: &str) -> Result<()> {
+        self.check_failure("abort_upload")?;
+        let mut state = self.state.write();
+        let upload = state.uploads.remove(upload_id).ok_or_else(|| no_such_upload(upload_id))?;
+        // drop the pending file entry like PikPak does for never completed uploads
+        let pending = state
+            .files
+            .get(&upload.file_id)
+            .map(|file| file.phase != "PHASE_TYPE_COMPLETE")
+            .unwrap_or(false);
+        if pending {
+            state.files.remove(&upload.file_id);
+        }
+        Ok(())
+    }
+
+    fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
+        self.check_failure("download")?;
+        let file_id = url.strip_prefix(URL_SCHEME).context("invalid download url")?;
+        let state = self.state.read();
+        let content = state.contents.get(file_id).context("file not found")?;
+        let start = std::cmp::min(start_pos as usize, content.len());
+        let end = std::cmp::min(start + size, content.len());
+        Ok(content.slice(start..end))
+    }
+
+    fn get_download_url(&self, file_id: &str) -> Result<DownloadUrl> {
+        self.check_failure("get_download_url")?;
+        let state = self.state.read();
+        let file = state.files.get(file_id).context("file not found")?;
+        Ok(DownloadUrl {
+            url: file.web_content_link.clone(),
+            expires_at: None,
+        })
+    }
+
+    fn get_quota(&self) -> Result<(u64, u64)> {
+        self.check_failure("get_quota")?;
+        let state = self.state.read();
+        let used = state.contents.values().map(|c| c.len() as u64).sum();
+        Ok((used, state.total_size))
+    }
+}
+
+fn content_hash(data: &[u8]) -> String {
+    let mut hasher = GcidHasher::default();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn no_such_upload(upload_id: &str) -> UploadError {
+    UploadError::Oss {
+        status: 404,
+        code: "NoSuchUpload".to_string(),
+        message: format!("upload {} does not exist", upload_id),
+        request_id: String::new(),
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fs;
+use std::io::{self, Read};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
@@ -29,13 +30,64 @@ use base64::encode;
 
 
 
+mod backend;
+#[cfg(test)]
+pub mod local_server;
+#[cfg(test)]
+pub mod memory;
 pub mod model;
+mod oss;
+mod rate_limit;
 
+pub use backend::DriveBackend;
+pub use oss::UploadError;
+pub use rate_limit::RateSchedule;
+use rate_limit::{LimitedReader, RateLimiter};
 pub use model::*;
 pub use model::{PikpakFile, DateTime, FileType};
 
+/// Failure of a download from the CDN
+#[derive(Debug, Clone)]
+pub enum DownloadError {
+    /// The download link expired or was revoked, a new one is needed
+    LinkExpired(u16),
+    Status(u16),
+    /// The connection broke off after `received` bytes arrived
+    Interrupted { received: Bytes, reason: String },
+}
+
+impl DownloadError {
+    /// Whether trying again may succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::LinkExpired(_) | DownloadError::Interrupted { .. } => true,
+            DownloadError::Status(status) => *status >= 500 || *status == 408 || *status == 429,
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::LinkExpired(status) => {
+                write!(f, "download link expired with status {}", status)
+            }
+            DownloadError::Status(status) => write!(f, "download failed with status {}", status),
+            DownloadError::Interrupted { received, reason } => write!(
+                f,
+                "download interrupted after {} bytes: {}",
+                received.len(),
+                reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
 const ORIGIN: &str = "https://api-drive.mypikpak.com/drive/v1/files";
 const REFERER: &str = "https://api-drive.mypikpak.com/drive/v1/files";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 const UA: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/92.0.4515.131 Safari/537.36";
 
 #[derive(Debug, Clone)]
@@ -43,6 +95,12 @@ pub struct DriveConfig {
     pub api_base_url: String,
     pub refresh_token_url: String,
     pub workdir: Option<PathBuf>,
+    /// `Host` header sent when creating files, `None` uses the host of `api_base_url`
+    pub api_host: Option<String>,
+    /// OSS base url overriding the endpoint returned by PikPak, e.g. `http://127.0.0.1:8080`
+    pub oss_endpoint: Option<String>,
+    pub max_download_rate: RateSchedule,
+    pub max_upload_rate: RateSchedule,
 }
 
 
@@ -55,6 +113,8 @@ pub struct PikpakDrive {
     credentials: Arc<RwLock<Credentials>>,
     drive_id: Option<String>,
     pub nick_name: Option<String>,
+    download_limiter: Option<Arc<RateLimiter>>,
+    upload_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl PikpakDrive {
@@ -75,14 +135,21 @@ impl PikpakDrive {
             // See also https://github.com/hyperium/hyper/issues/2136
             .pool_idle_timeout(Duration::from_secs(50))
             .connect_timeout(Duration::from_secs(10))
-            .timeout(Duration::from_secs(30))
+            .timeout(REQUEST_TIMEOUT)
             .build()?;
+        let limiter = |schedule: &RateSchedule| {
+            (!schedule.is_unlimited()).then(|| Arc::new(RateLimiter::new(schedule.clone())))
+        };
+        let download_limiter = limiter(&config.max_download_rate);
+        let upload_limiter = limiter(&config.max_upload_rate);
         let mut drive = Self {
             config,
             client,
             credentials: Arc::new(RwLock::new(credentials)),
             drive_id: None,
             nick_name: None,
+            download_limiter,
+            upload_limiter,
         };
 
         let (tx, rx) = oneshot::channel();
@@ -258,6 +325,13 @@ impl PikpakDrive {
         Ok(refresh_token_from_file.unwrap().trim().to_string())
     }
 
+    fn oss_url(&self, oss_args: &OssArgs) -> String {
+        match self.config.oss_endpoint.as_ref() {
+            Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), oss_args.key),
+            None => format!("https://{}/{}", oss_args.endpoint, oss_args.key),
+        }
+    }
+
     fn drive_id(&self) -> Result<&str> {
         self.drive_id.as_deref().context("missing drive_id")
     }
@@ -460,28 +534,19 @@ impl PikpakDrive {
         }
     }
 
-
-
-
-
-
-    pub fn list_all(&self, parent_file_id: &str) -> Result<Vec<PikpakFile>> {
-        debug!("drive list_all: {}", parent_file_id);
-        let mut files = Vec::new();
-        let mut marker = None;
-        loop {
-            let res = self.list(parent_file_id, marker.as_deref())?;
-            //println!("drive list  result is:{:?}",res);
-            files.extend(res.files.into_iter());
-            if res.next_page_token.is_empty() {
-                break;
-            }
-            marker = Some(res.next_page_token);
-        }
-        Ok(files)
+    pub fn hmac_authorization(&self, req:&reqwest::blocking::Request,time:&str,oss_args:&OssArgs)->String{
+        let message = format!("{}\n\n{}\n{}\nx-oss-security-token:{}\n/{}{}?{}",req.method().as_str(),req.headers().get(reqwest::header::CONTENT_TYPE).unwrap().to_str().unwrap(),time,oss_args.security_token,oss_args.bucket,req.url().path(),req.url().query().unwrap());
+        let key = &oss_args.access_key_secret;
+      
+        let mut hasher = HmacSha::from(key, &message, Sha1::default());
+        let result = hasher.compute_digest();
+        let signature_base64 = base64::encode(&result);
+        signature_base64
     }
+}
 
-    pub fn list(&self, parent_file_id: &str, marker: Option<&str>) -> Result<ListFileResponse> {
+impl DriveBackend for PikpakDrive {
+    fn list(&self, parent_file_id: &str, marker: Option<&str>) -> Result<ListFileResponse> {
         let drive_id = self.drive_id()?;
         let pagetoken = marker.unwrap_or("");
         let mut data = HashMap::new();
@@ -491,7 +556,7 @@ impl PikpakDrive {
     }
 
 
-    pub fn create_folder(&self, parent_id:&str, folder_name: &str) -> Result<CreateFolderResponse> {
+    fn create_folder(&self, parent_id:&str, folder_name: &str) -> Result<CreateFolderResponse> {
         debug!("drive create folder {}", folder_name);
         let rurl = format!("{}",self.config.api_base_url);
         let req = CreateFolderRequest{kind:"drive#folder",name:folder_name,parent_id:parent_id};
@@ -499,22 +564,36 @@ impl PikpakDrive {
     }
 
 
-    pub fn remove_file(&self, file_id: &str) -> Result<TaskResponse> {
+    fn remove_file(&self, file_id: &str) -> Result<TaskResponse> {
         debug!("drive remove file {}", file_id);
         let rurl = format!("{}:batchDelete",self.config.api_base_url);
         let req = DelFileRequest{ids:vec![file_id.to_string()]};
         self.post_request(rurl,&req).and_then(|res| res.context("expect response"))
     }
 
+    fn trash_file(&self, file_id: &str) -> Result<TaskResponse> {
+        debug!("drive trash file {}", file_id);
+        let rurl = format!("{}:batchTrash",self.config.api_base_url);
+        let req = DelFileRequest{ids:vec![file_id.to_string()]};
+        self.post_request(rurl,&req).and_then(|res| res.context("expect response"))
+    }
+
+    fn untrash_file(&self, file_id: &str) -> Result<TaskResponse> {
+        debug!("drive untrash file {}", file_id);
+        let rurl = format!("{}:batchUntrash",self.config.api_base_url);
+        let req = DelFileRequest{ids:vec![file_id.to_string()]};
+        self.post_request(rurl,&req).and_then(|res| res.context("expect response"))
+    }
+
 
-    pub fn move_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse>  {
+    fn move_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse>  {
         let rurl = format!("{}:batchMove",self.config.api_base_url);
         let req = MoveFileRequest{ids:vec![file_id.to_string()],to:MoveTo { parent_id: new_parent_id.to_string()}};
         self.post_request(rurl,&req).and_then(|res| res.context("expect response"))
     }
 
 
-    pub fn rename_file(&self, file_id: &str, new_name: &str) -> Result<PikpakFile> {
+    fn rename_file(&self, file_id: &str, new_name: &str) -> Result<PikpakFile> {
         let rurl = format!("{}/{}",self.config.api_base_url,file_id);
         let req = RenameFileRequest{name:new_name};
         self.patch_request(rurl,&req).and_then(|res| res.context("expect response"))
@@ -522,13 +601,13 @@ impl PikpakDrive {
 
 
 
-    pub fn copy_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse> {
+    fn copy_file(&self, file_id: &str, new_parent_id: &str) -> Result<TaskResponse> {
         let rurl = format!("{}:batchCopy",self.config.api_base_url);
         let req = MoveFileRequest{ids:vec![file_id.to_string()],to:MoveTo { parent_id: new_parent_id.to_string()}};
         self.post_request(rurl,&req).and_then(|res| res.context("expect response"))
     }
 
-    pub fn create_file_with_proof(&self,name: &str, parent_file_id: &str, hash:&str, size: u64) ->  Result<UploadResponse> {
+    fn create_file_with_proof(&self,name: &str, parent_file_id: &str, hash:&str, size: u64) ->  Result<UploadResponse> {
         debug!("drive create file with proof {}", name);
         let url = format!("{}",self.config.api_base_url);
         let req = UploadRequest{
@@ -540,39 +619,29 @@ impl PikpakDrive {
             objProvider: ObjProvider { provider: "UPLOAD_TYPE_UNKNOWN".to_string() },
 		    parent_id:parent_file_id.to_string(),
         };
-        let payload = serde_json::to_string(&req).unwrap();
-        let access_token_key = "access_token".to_string();
-        let access_token = self.access_token().unwrap();
+        let payload = serde_json::to_string(&req)?;
+        let access_token = self.access_token()?;
 
-        let res = self.client.post(url)
-            .header(reqwest::header::CONTENT_LENGTH, payload.len())
-            .header(reqwest::header::HOST, "api-drive.mypikpak.com")
+        let mut req = self.client.post(url)
+            .header(reqwest::header::CONTENT_LENGTH, payload.len());
+        if let Some(host) = self.config.api_host.as_ref() {
+            req = req.header(reqwest::header::HOST, host);
+        }
+        let res = req
             .header(reqwest::header::AUTHORIZATION, format!("Bearer {}",access_token))
             .body(payload)
-            .send();
-
-        let body = match res {
-            Ok(res) => res.text().unwrap(),
-            Err(err) => {
-                error!("{:?}", err);
-                return Err(err.into());
-            }
-        };
-            
-        let result = match serde_json::from_str::<UploadResponse>(&body) {
-            Ok(result) => result,
-            Err(e) => {
-                error!(error = %e, "create_file_with_proof");
-                return Err(e.into());
-            }
-        };
-    
+            .send()?;
+        let body = oss::check_body(res)?;
+        let result = serde_json::from_str::<UploadResponse>(&body).map_err(|e| {
+            error!(error = %e, "create_file_with_proof");
+            e
+        })?;
         Ok(result)
     }
 
 
-    pub fn get_pre_upload_info(&self,oss_args:&OssArgs) -> Result<String> {
-        let mut url = format!("https://{}/{}?uploads",oss_args.endpoint,oss_args.key);
+    fn get_pre_upload_info(&self,oss_args:&OssArgs) -> Result<String> {
+        let url = format!("{}?uploads",self.oss_url(oss_args));
         let now = SystemTime::now();
         let gmt = httpdate::fmt_http_date(now);
         let mut req = self.client.post(url)
@@ -584,31 +653,36 @@ impl PikpakDrive {
         let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
         let header_auth = HeaderValue::from_str(&oss_header).unwrap();
         req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req);
-        let body = match res {
-            Ok(res) => res.text().unwrap(),
-            Err(err) => {
-                error!("{:?}", err);
-                return Err(err.into());
-            }
-        };
-        let result: InitiateMultipartUploadResult = from_str(&body).unwrap();
-        Ok(result.UploadId.clone())
+        let res = self.client.execute(req)?;
+        let body = oss::check_body(res)?;
+        let result: InitiateMultipartUploadResult = from_str(&body)
+            .map_err(|_| UploadError::InvalidResponse(body.clone()))?;
+        Ok(result.UploadId)
     }
 
-    pub fn upload_chunk(&self, file:&PikpakFile, oss_args:&OssArgs, upload_id:&str, current_chunk:u64,body: Bytes) -> Result<(PartInfo)> {
+    fn upload_chunk(&self, file:&PikpakFile, oss_args:&OssArgs, upload_id:&str, current_chunk:u64,body: Bytes) -> Result<PartInfo> {
         debug!(file_name=%file.name,upload_id = upload_id,current_chunk=current_chunk, "upload_chunk");
         let encoded: String = form_urlencoded::Serializer::new(String::new())
         .append_pair("partNumber", current_chunk.to_string().as_str())
         .append_pair("uploadId", upload_id)
         .finish();
 
-        let url = format!("https://{}/{}?{}",oss_args.endpoint,oss_args.key,encoded);
+        let url = format!("{}?{}",self.oss_url(oss_args),encoded);
    
         let now = SystemTime::now();
         let gmt = httpdate::fmt_http_date(now);
-        let mut req = self.client.put(url)
-            .body(body)
+        let req = match &self.upload_limiter {
+            Some(limiter) => {
+                let len = body.len() as u64;
+                let reader = LimitedReader::new(io::Cursor::new(body), limiter.clone());
+                self.client
+                    .put(url)
+                    .body(reqwest::blocking::Body::sized(reader, len))
+                    .timeout(REQUEST_TIMEOUT + limiter.transfer_time(len))
+            }
+            None => self.client.put(url).body(body),
+        };
+        let mut req = req
             .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
             .header("X-Oss-Security-Token", &oss_args.security_token)
             .header("Date", &gmt).build()?;
@@ -616,21 +690,15 @@ impl PikpakDrive {
         let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
         let header_auth = HeaderValue::from_str(&oss_header).unwrap();
         req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req);
-        // let body = match res {
-        //     Ok(res) => res.text().unwrap(),
-        //     Err(err) => {
-        //         error!("{:?}", err);
-        //         return Err(err.into());
-        //     }
-        // };
-        //let body = &res.text().await?;
+        let res = oss::check_status(self.client.execute(req)?)?;
+        let etag = res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .filter(|etag| !etag.is_empty())
+            .ok_or(UploadError::MissingETag { part_number: current_chunk })?
+            .to_string();
 
-        let etag  = match &res.unwrap().headers().get("ETag") {
-            Some(etag) => etag.to_str().unwrap().to_string(),
-            None => "".to_string(),
-        };
-            
         let part = PartInfo {
             PartNumber: PartNumber { PartNumber: current_chunk },
             ETag: etag,
@@ -639,9 +707,9 @@ impl PikpakDrive {
         Ok(part)
     }
 
-    pub fn complete_upload(&self,file:&PikpakFile, upload_tags:String, oss_args:&OssArgs, upload_id:&str)-> Result<()> {
+    fn complete_upload(&self,file:&PikpakFile, upload_tags:String, oss_args:&OssArgs, upload_id:&str)-> Result<()> {
         debug!(file = %file.name, "complete_upload");
-        let url = format!("https://{}/{}?uploadId={}",oss_args.endpoint,oss_args.key,upload_id);
+        let url = format!("{}?uploadId={}",self.oss_url(oss_args),upload_id);
         let now = SystemTime::now();
         let gmt = httpdate::fmt_http_date(now);
         let mut req = self.client.post(url)
@@ -653,47 +721,62 @@ impl PikpakDrive {
         let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
         let header_auth = HeaderValue::from_str(&oss_header).unwrap();
         req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req);
-
-        let body = match res {
-            Ok(res) => res.text().unwrap(),
-            Err(err) => {
-                error!("{:?}", err);
-                return Err(err.into());
-            }
-        };
+        let res = self.client.execute(req)?;
+        let body = oss::check_body(res)?;
         debug!(file = %file.name, res_body = body, "complete_upload_response");
         Ok(())
     }
 
-    pub fn hmac_authorization(&self, req:&reqwest::blocking::Request,time:&str,oss_args:&OssArgs)->String{
-        let message = format!("{}\n\n{}\n{}\nx-oss-security-token:{}\n/{}{}?{}",req.method().as_str(),req.headers().get(reqwest::header::CONTENT_TYPE).unwrap().to_str().unwrap(),time,oss_args.security_token,oss_args.bucket,req.url().path(),req.url().query().unwrap());
-        let key = &oss_args.access_key_secret;
-      
-        let mut hasher = HmacSha::from(key, &message, Sha1::default());
-        let result = hasher.compute_digest();
-        let signature_base64 = base64::encode(&result);
-        signature_base64
+    fn abort_upload(&self, oss_args: &OssArgs, upload_id: &str) -> Result<()> {
+        debug!(key = %oss_args.key, upload_id = upload_id, "abort_upload");
+        let url = format!("{}?uploadId={}", self.oss_url(oss_args), upload_id);
+        let gmt = httpdate::fmt_http_date(SystemTime::now());
+        let mut req = self.client.delete(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header("X-Oss-Security-Token", &oss_args.security_token)
+            .header("Date", &gmt).build()?;
+        let oss_sign: String = self.hmac_authorization(&req, &gmt, oss_args);
+        let oss_header = format!("OSS {}:{}", &oss_args.access_key_id, &oss_sign);
+        req.headers_mut().insert(reqwest::header::AUTHORIZATION, HeaderValue::from_str(&oss_header)?);
+        oss::check_status(self.client.execute(req)?)?;
+        Ok(())
     }
 
-
-
-    pub fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
+    fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
         debug!(url = %url, "download file");
         use reqwest::header::RANGE;
         let end_pos = start_pos + size as u64 - 1;
         debug!(url = %url, start = start_pos, end = end_pos, "download file");
         let range = format!("bytes={}-{}", start_pos, end_pos);
-        let res = self
-            .client
-            .get(url)
-            .header(RANGE, range)
-            .send()?
-            .error_for_status()?;
-        Ok(res.bytes()?)
+        let mut req = self.client.get(url).header(RANGE, range);
+        if let Some(limiter) = &self.download_limiter {
+            req = req.timeout(REQUEST_TIMEOUT + limiter.transfer_time(size as u64));
+        }
+        let res = req.send()?;
+        let status = res.status();
+        if status == StatusCode::FORBIDDEN || status == StatusCode::GONE {
+            return Err(DownloadError::LinkExpired(status.as_u16()).into());
+        }
+        if !status.is_success() {
+            return Err(DownloadError::Status(status.as_u16()).into());
+        }
+        let mut reader: Box<dyn Read> = match &self.download_limiter {
+            Some(limiter) => Box::new(LimitedReader::new(res, limiter.clone())),
+            None => Box::new(res),
+        };
+        let mut data = Vec::with_capacity(size);
+        // keep what arrived so the caller can resume from there
+        if let Err(err) = reader.read_to_end(&mut data) {
+            return Err(DownloadError::Interrupted {
+                received: Bytes::from(data),
+                reason: err.to_string(),
+            }
+            .into());
+        }
+        Ok(Bytes::from(data))
     }
 
-    pub fn get_download_url(&self, file_id: &str) -> Result<String> {
+    fn get_download_url(&self, file_id: &str) -> Result<DownloadUrl> {
         debug!(file_id = %file_id, "get download url");
         let mut rurl = format!("{}/{}",self.config.api_base_url,file_id.to_string());
         let url = rurl;
@@ -701,20 +784,28 @@ impl PikpakDrive {
         data.insert("file_id", file_id);
         let res: PikpakFile = self.request(url,&data)?.context("expect response")?;
         if res.mime_type.contains("video/"){
-            Ok(res.medias[0].link.url.clone())
+            let link = &res.medias[0].link;
+            Ok(DownloadUrl {
+                url: link.url.clone(),
+                expires_at: link.expires_at(),
+            })
         }else{
-            Ok(res.web_content_link.clone())
+            Ok(DownloadUrl {
+                url: res.web_content_link.clone(),
+                expires_at: None,
+            })
         }
         //Ok("".to_string())
     }
 
-    pub fn get_quota(&self) -> Result<(u64, u64)> {
-        let drive_id = self.drive_id()?;
-        let mut data = HashMap::new();
-        data.insert("drive_id", drive_id);
-        let res: GetDriveResponse = self
-            .request(format!("{}/v2/drive/get", self.config.api_base_url), &data)?
-            .context("expect response")?;
-        Ok((res.used_size, res.total_size))
+    fn get_quota(&self) -> Result<(u64, u64)> {
+        // the about endpoint is a sibling of the files endpoint
+        let base_url = self.config.api_base_url.trim_end_matches('/');
+        let url = format!("{}/about", base_url.strip_suffix("/files").unwrap_or(base_url));
+        let data: HashMap<&str, &str> = HashMap::new();
+        let res: AboutResponse = self.request(url, &data)?.context("expect response")?;
+        let used_size = res.quota.usage.parse().context("invalid quota usage")?;
+        let total_size = res.quota.limit.parse().context("invalid quota limit")?;
+        Ok((used_size, total_size))
     }
 }
@@ -2,7 +2,7 @@ use std::ops;
 use std::time::SystemTime;
 
 use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 
 
@@ -61,10 +61,25 @@ pub struct GetFileDownloadUrlResponse {
     pub expiration: String,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct GetDriveResponse {
-    pub total_size: u64,
-    pub used_size: u64,
+/// Download link of a file
+#[derive(Debug, Clone)]
+pub struct DownloadUrl {
+    pub url: String,
+    /// When the link stops working, if known
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Response of the `about` endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AboutResponse {
+    pub quota: Quota,
+}
+
+/// Storage quota, sizes in bytes as decimal strings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    pub limit: String,
+    pub usage: String,
 }
 
 
@@ -76,7 +91,7 @@ pub enum FileType {
 }
 
 
-#[derive(Debug, Clone,Serialize)]
+#[derive(Debug, Clone)]
 pub struct DateTime(SystemTime);
 
 impl DateTime {
@@ -93,6 +108,15 @@ impl<'a> Deserialize<'a> for DateTime {
     }
 }
 
+impl Serialize for DateTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let dt = OffsetDateTime::from(self.0)
+            .format(&Rfc3339)
+            .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&dt)
+    }
+}
+
 impl ops::Deref for DateTime {
     type Target = SystemTime;
 
@@ -122,6 +146,16 @@ pub struct PikpakFile {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Link {
     pub url: String,
+    #[serde(default)]
+    pub expire: String,
+}
+
+impl Link {
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        OffsetDateTime::parse(&self.expire, &Rfc3339)
+            .ok()
+            .map(SystemTime::from)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -234,10 +268,19 @@ pub struct PartNumber {
 #[derive(Debug, Clone,Serialize, Deserialize)]
 pub struct UploadResponse {
     pub upload_type: String,
-    pub resumable: Resumable,
+    /// Missing when the server already has the content
+    pub resumable: Option<Resumable>,
     pub file: PikpakFile,
 }
 
+impl UploadResponse {
+    /// Whether the file was created from content already on the server, so
+    /// nothing has to be uploaded (秒传)
+    pub fn is_instant(&self) -> bool {
+        self.resumable.is_none() || self.file.phase == "PHASE_TYPE_COMPLETE"
+    }
+}
+
 
 #[derive(Debug, Clone,Serialize, Deserialize)]
 pub struct Resumable {
@@ -269,6 +312,18 @@ pub struct InitiateMultipartUploadResult {
 
 
 impl PikpakFile {
+    /// Identifies the content of the file, changes whenever the content does
+    pub fn content_version(&self) -> String {
+        match self.hash.as_deref() {
+            Some(hash) if !hash.is_empty() => hash.to_string(),
+            _ => self
+                .modified_time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_nanos().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
     pub fn new_root() -> Self {
         let now = SystemTime::now();
         Self {
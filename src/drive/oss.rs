@@ -0,0 +1,164 @@
+//! Aliyun OSS response checking
+use std::fmt;
+
+use quick_xml::de::from_str;
+use reqwest::blocking::Response;
+use serde::Deserialize;
+
+/// Error body returned by OSS
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Deserialize)]
+struct OssErrorBody {
+    Code: String,
+    Message: String,
+    #[serde(default)]
+    RequestId: String,
+}
+
+/// Failure of an OSS multipart upload request
+#[derive(Debug, Clone)]
+pub enum UploadError {
+    /// OSS rejected the request
+    Oss {
+        status: u16,
+        code: String,
+        message: String,
+        request_id: String,
+    },
+    /// Non-success status without a parsable error body
+    Status(u16),
+    /// A part was accepted but no ETag came back
+    MissingETag { part_number: u64 },
+    InvalidResponse(String),
+}
+
+impl UploadError {
+    /// Whether retrying the same request may succeed, otherwise the
+    /// multipart upload has to be started over
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            UploadError::Oss { status, .. } | UploadError::Status(status) => {
+                *status >= 500 || *status == 408 || *status == 429
+            }
+            UploadError::MissingETag { .. } | UploadError::InvalidResponse(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::Oss {
+                status,
+                code,
+                message,
+                request_id,
+            } => write!(
+                f,
+                "oss error {} {}: {} (request id {})",
+                status, code, message, request_id
+            ),
+            UploadError::Status(status) => write!(f, "oss request failed with status {}", status),
+            UploadError::MissingETag { part_number } => {
+                write!(f, "oss response for part {} has no ETag", part_number)
+            }
+            UploadError::InvalidResponse(body) => write!(f, "invalid oss response: {}", body),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// Parse an OSS error body, OSS may also return one with a success status
+fn parse_error(status: u16, body: &str) -> Option<UploadError> {
+    if !body.contains("<Error>") {
+        return None;
+    }
+    let err: OssErrorBody = from_str(body).ok()?;
+    Some(UploadError::Oss {
+        status,
+        code: err.Code,
+        message: err.Message,
+        request_id: err.RequestId,
+    })
+}
+
+/// Fail on a non-success status
+pub fn check_status(res: Response) -> Result<Response, UploadError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let body = res.text().unwrap_or_default();
+    Err(parse_error(status.as_u16(), &body).unwrap_or(UploadError::Status(status.as_u16())))
+}
+
+/// Body of a successful response that must not contain an error document
+pub fn check_body(res: Response) -> Result<String, UploadError> {
+    let res = check_status(res)?;
+    let status = res.status().as_u16();
+    let body = res
+        .text()
+        .map_err(|err| UploadError::InvalidResponse(err.to_string()))?;
+    match parse_error(status, &body) {
+        Some(err) => Err(err),
+        None => Ok(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oss_error(status: u16) -> UploadError {
+        UploadError::Oss {
+            status,
+            code: "InternalError".to_string(),
+            message: "We encountered an internal error".to_string(),
+            request_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn parses_error_bodies() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Error>
+  <Code>NoSuchUpload</Code>
+  <Message>The specified upload does not exist.</Message>
+  <RequestId>5C3D9175B6FC201293AD4890</RequestId>
+  <HostId>bucket.oss-cn-hangzhou.aliyuncs.com</HostId>
+</Error>"#;
+        match parse_error(404, body) {
+            Some(UploadError::Oss {
+                status,
+                code,
+                message,
+                request_id,
+            }) => {
+                assert_eq!(status, 404);
+                assert_eq!(code, "NoSuchUpload");
+                assert_eq!(message, "The specified upload does not exist.");
+                assert_eq!(request_id, "5C3D9175B6FC201293AD4890");
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+        // OSS may send an error document with a success status
+        assert!(matches!(parse_error(200, body), Some(UploadError::Oss { status: 200, .. })));
+        let body = "<InitiateMultipartUploadResult><UploadId>0004B999EF5A239BB9138C6227D6****</UploadId></InitiateMultipartUploadResult>";
+        assert!(parse_error(200, body).is_none());
+        assert!(parse_error(502, "<html>Bad Gateway</html>").is_none());
+    }
+
+    #[test]
+    fn retryable_errors() {
+        for status in [408, 429, 500, 503] {
+            assert!(oss_error(status).is_retryable(), "{}", status);
+            assert!(UploadError::Status(status).is_retryable(), "{}", status);
+        }
+        for status in [400, 403, 404] {
+            assert!(!oss_error(status).is_retryable(), "{}", status);
+            assert!(!UploadError::Status(status).is_retryable(), "{}", status);
+        }
+        assert!(UploadError::MissingETag { part_number: 1 }.is_retryable());
+    }
+}
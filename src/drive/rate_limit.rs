@@ -0,0 +1,249 @@
+//! Token bucket bandwidth limits shared by all transfers in one direction
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use parking_lot::Mutex;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+/// Tokens are taken for at most this many bytes at a time so concurrent
+/// transfers interleave
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Rate applying between two times of the day, in minutes since midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Period {
+    start: u32,
+    end: u32,
+    rate: u64,
+}
+
+impl Period {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            // wraps around midnight
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Bandwidth limit in bytes per second, 0 means unlimited.
+///
+/// Parsed from a comma separated list of rates with an optional K/M/G
+/// suffix, rates prefixed with a local time range only apply during that
+/// range, e.g. `08:00-23:00=1M,4M`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateSchedule {
+    rate: u64,
+    periods: Vec<Period>,
+}
+
+impl RateSchedule {
+    fn rate_at(&self, minute: u32) -> u64 {
+        self.periods
+            .iter()
+            .find(|period| period.contains(minute))
+            .map(|period| period.rate)
+            .unwrap_or(self.rate)
+    }
+
+    fn rates(&self) -> impl Iterator<Item = u64> + '_ {
+        std::iter::once(self.rate).chain(self.periods.iter().map(|period| period.rate))
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.rates().all(|rate| rate == 0)
+    }
+}
+
+fn parse_rate(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let multiplier = match unit {
+        'B' => 1,
+        'K' => 1024,
+        'M' => 1024 * 1024,
+        'G' => 1024 * 1024 * 1024,
+        _ => bail!("invalid rate unit in {:?}", s),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid rate {:?}", s))?;
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("rate {:?} is too large", s))
+}
+
+fn parse_time(s: &str) -> anyhow::Result<u32> {
+    let (hour, minute) = s
+        .trim()
+        .split_once(':')
+        .with_context(|| format!("invalid time {:?}, expected HH:MM", s))?;
+    let hour: u32 = hour.parse().with_context(|| format!("invalid time {:?}", s))?;
+    let minute: u32 = minute.parse().with_context(|| format!("invalid time {:?}", s))?;
+    if hour > 24 || minute >= 60 || hour * 60 + minute > MINUTES_PER_DAY {
+        bail!("invalid time {:?}", s);
+    }
+    Ok(hour * 60 + minute)
+}
+
+impl FromStr for RateSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut schedule = RateSchedule::default();
+        for item in s.split(',').filter(|item| !item.trim().is_empty()) {
+            match item.split_once('=') {
+                Some((range, rate)) => {
+                    let (start, end) = range
+                        .split_once('-')
+                        .with_context(|| format!("invalid time range {:?}", range))?;
+                    schedule.periods.push(Period {
+                        start: parse_time(start)?,
+                        end: parse_time(end)?,
+                        rate: parse_rate(rate)?,
+                    });
+                }
+                None => schedule.rate = parse_rate(item)?,
+            }
+        }
+        Ok(schedule)
+    }
+}
+
+/// Minutes since local midnight
+fn local_minute() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default() as libc::time_t;
+    // SAFETY: `localtime_r` only writes to the `tm` passed in
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return ((now / 60) as u64 % u64::from(MINUTES_PER_DAY)) as u32;
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    schedule: RateSchedule,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: RateSchedule) -> Self {
+        Self {
+            schedule,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` may be transferred
+    pub fn acquire(&self, bytes: usize) {
+        let rate = self.schedule.rate_at(local_minute()) as f64;
+        if rate == 0.0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            // allow bursts of up to one second worth of tokens
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.updated = now;
+            // the tokens may go negative, later callers then wait longer
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// How long transferring `bytes` may take at the lowest configured rate
+    pub fn transfer_time(&self, bytes: u64) -> Duration {
+        match self.schedule.rates().filter(|rate| *rate > 0).min() {
+            Some(rate) => Duration::from_secs_f64(bytes as f64 / rate as f64),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// Reader taking tokens from a `RateLimiter` for everything read
+pub struct LimitedReader<R> {
+    inner: R,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), CHUNK_SIZE);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.limiter.acquire(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("100").unwrap(), 100);
+        assert_eq!(parse_rate(" 100b ").unwrap(), 100);
+        assert_eq!(parse_rate("2k").unwrap(), 2 * 1024);
+        assert_eq!(parse_rate("3M").unwrap(), 3 * 1024 * 1024);
+        assert_eq!(parse_rate("1G").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_rate("0").unwrap(), 0);
+        assert_eq!(parse_rate("0M").unwrap(), 0);
+        assert_eq!(parse_rate(&u64::MAX.to_string()).unwrap(), u64::MAX);
+        for invalid in ["", "M", "abc", "1.5M", "-1", "10T", "18446744073709551616"] {
+            assert!(parse_rate(invalid).is_err(), "{:?}", invalid);
+        }
+        // overflows once the unit is applied
+        assert!(parse_rate("17179869184G").is_err());
+        assert!(parse_rate(&format!("{}K", u64::MAX / 1024 + 1)).is_err());
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let schedule: RateSchedule = "08:00-23:00=1M,4M".parse().unwrap();
+        assert_eq!(schedule.rate_at(7 * 60 + 59), 4 * 1024 * 1024);
+        assert_eq!(schedule.rate_at(8 * 60), 1024 * 1024);
+        assert_eq!(schedule.rate_at(23 * 60), 4 * 1024 * 1024);
+        assert!(!schedule.is_unlimited());
+        assert!("0".parse::<RateSchedule>().unwrap().is_unlimited());
+        assert!("08:00-23:00=99999999999G".parse::<RateSchedule>().is_err());
+        assert!("25:00-23:00=1M".parse::<RateSchedule>().is_err());
+    }
+}
@@ -8,6 +8,15 @@ pub enum Error {
     ApiCallFailed,
     UploadFailed,
     NotFound,
+    FileTooLarge,
+    IsDirectory,
+    Busy,
+    Io,
+    NotSupported,
+    Exists,
+    NotDirectory,
+    NotEmpty,
+    InvalidArgument,
 }
 
 impl From<Error> for c_int {
@@ -19,6 +28,15 @@ impl From<Error> for c_int {
             Error::NotFound => libc::ENOENT,
             Error::ApiCallFailed => libc::EIO,
             Error::UploadFailed => libc::EIO,
+            Error::FileTooLarge => libc::EFBIG,
+            Error::IsDirectory => libc::EISDIR,
+            Error::Busy => libc::EBUSY,
+            Error::Io => libc::EIO,
+            Error::NotSupported => libc::EOPNOTSUPP,
+            Error::Exists => libc::EEXIST,
+            Error::NotDirectory => libc::ENOTDIR,
+            Error::NotEmpty => libc::ENOTEMPTY,
+            Error::InvalidArgument => libc::EINVAL,
         }
     }
 }
@@ -1,100 +1,510 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use bytes::Bytes;
-use tracing::debug;
+use bytes::{Bytes, BytesMut};
+use moka::sync::{Cache as MokaCache, CacheBuilder};
+use parking_lot::{Condvar, Mutex};
+use tracing::{debug, error, warn};
 
+use crate::disk_cache::DiskCache;
 use crate::error::Error;
-use crate::PikpakDrive;
+use crate::drive::{DownloadError, DownloadUrl, DriveBackend};
+use crate::thread_pool::ThreadPool;
 
+/// Reads landing in the same or the next block as the previous read count as
+/// sequential, read-ahead kicks in after this many of them in a row
+const SEQUENTIAL_READS: u32 = 2;
+/// Download links are refreshed this long before they expire
+const URL_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Block being downloaded, filled by whoever started the download
+#[derive(Debug, Default)]
+struct Block {
+    data: Mutex<Option<Result<Bytes, Error>>>,
+    ready: Condvar,
+}
+
+impl Block {
+    fn set(&self, res: Result<Bytes, Error>) {
+        *self.data.lock() = Some(res);
+        self.ready.notify_all();
+    }
+
+    fn wait(&self) -> Result<Bytes, Error> {
+        let mut data = self.data.lock();
+        loop {
+            if let Some(res) = data.as_ref() {
+                return res.clone();
+            }
+            self.ready.wait(&mut data);
+        }
+    }
+}
+
+/// File being read
 #[derive(Debug)]
-struct CachedFile {
+struct FileInfo {
+    id: String,
+    // content version, see `PikpakFile::content_version`
+    version: String,
+    size: u64,
+}
+
+/// `read_buffer_size` bytes at `index * read_buffer_size` of a file
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BlockKey {
     file_id: String,
-    file_size: u64,
-    start_pos: i64,
-    buffer: Bytes,
+    version: String,
+    index: u64,
 }
 
 #[derive(Debug)]
-pub struct FileCache {
-    drive: PikpakDrive,
-    read_buffer_size: usize,
+struct CachedFile {
+    // prefetch jobs only hold a weak reference, they are dropped once the
+    // file handle is released
+    file: Arc<FileInfo>,
+    last_block: Option<u64>,
+    sequential_reads: u32,
+    // blocks before this one were already prefetched
+    prefetch_from: u64,
+}
+
+struct Downloader<D: DriveBackend> {
+    drive: D,
+    block_size: u64,
+    connections: usize,
+    retries: u32,
+    // return partial data instead of an error once retries are exhausted
+    short_reads: bool,
+    // file id -> download link
+    urls: MokaCache<String, DownloadUrl>,
+    // blocks shared by all file handles
+    blocks: MokaCache<BlockKey, Bytes>,
+    // blocks being downloaded, concurrent readers wait for the same download
+    in_flight: Mutex<HashMap<BlockKey, Arc<Block>>>,
+    disk_cache: Option<DiskCache>,
+}
+
+impl<D: DriveBackend> Downloader<D> {
+    fn get_block(&self, file: &FileInfo, index: u64) -> Result<Bytes, Error> {
+        let key = BlockKey {
+            file_id: file.id.clone(),
+            version: file.version.clone(),
+            index,
+        };
+        if let Some(data) = self.blocks.get(&key) {
+            return Ok(data);
+        }
+        let (block, downloading) = {
+            let mut in_flight = self.in_flight.lock();
+            match in_flight.get(&key) {
+                Some(block) => (block.clone(), false),
+                None => {
+                    let block = Arc::new(Block::default());
+                    in_flight.insert(key.clone(), block.clone());
+                    (block, true)
+                }
+            }
+        };
+        if !downloading {
+            debug!(file_id = %file.id, block = index, "wait for block download");
+            match block.wait() {
+                Ok(data) => return Ok(data),
+                // someone else's download failed, try again on our own
+                Err(err) => warn!(file_id = %file.id, block = index, error = ?err, "block download failed, retrying"),
+            }
+            return self.read_block(file, index);
+        }
+        let res = self.read_block(file, index);
+        match &res {
+            Ok(data) if data.len() == self.block_len(file, index) => {
+                self.blocks.insert(key.clone(), data.clone());
+            }
+            _ => {}
+        }
+        self.in_flight.lock().remove(&key);
+        block.set(res.clone());
+        res
+    }
+
+    fn download_url(&self, file_id: &str) -> anyhow::Result<String> {
+        if let Some(url) = self.urls.get(file_id) {
+            let fresh = url
+                .expires_at
+                .map(|expires_at| SystemTime::now() + URL_EXPIRY_MARGIN < expires_at)
+                .unwrap_or(true);
+            if fresh {
+                return Ok(url.url);
+            }
+        }
+        let url = self.drive.get_download_url(file_id)?;
+        debug!(file_id = %file_id, expires_at = ?url.expires_at, "download url resolved");
+        self.urls.insert(file_id.to_string(), url.clone());
+        Ok(url.url)
+    }
+
+    fn block_len(&self, file: &FileInfo, index: u64) -> usize {
+        std::cmp::min(self.block_size, file.size.saturating_sub(index * self.block_size)) as usize
+    }
+
+    fn read_block(&self, file: &FileInfo, index: u64) -> Result<Bytes, Error> {
+        let offset = index * self.block_size;
+        let size = self.block_len(file, index);
+        let disk_cache = match &self.disk_cache {
+            Some(disk_cache) => disk_cache,
+            None => return self.download(&file.id, offset, size),
+        };
+        if let Some(data) = disk_cache.get(&file.id, &file.version, index) {
+            if data.len() == size {
+                return Ok(data);
+            }
+        }
+        let data = self.download(&file.id, offset, size)?;
+        // partial blocks are not worth keeping
+        if data.len() == size {
+            disk_cache.put(&file.id, &file.version, index, &data);
+        }
+        Ok(data)
+    }
+
+    /// Download a range, retrying transient failures and resuming after the
+    /// bytes received so far
+    fn download(&self, file_id: &str, offset: u64, size: usize) -> Result<Bytes, Error> {
+        let mut data = BytesMut::new();
+        let mut attempt = 0;
+        loop {
+            let received = data.len();
+            let err = match self.download_range(file_id, offset + received as u64, size - received) {
+                Ok(part) if received == 0 => return Ok(part),
+                Ok(part) => {
+                    data.extend_from_slice(&part);
+                    return Ok(data.freeze());
+                }
+                Err(err) => err,
+            };
+            if let Some(DownloadError::Interrupted { received, .. }) = err.downcast_ref() {
+                data.extend_from_slice(received);
+            }
+            let retryable = err
+                .downcast_ref::<DownloadError>()
+                .map(DownloadError::is_retryable)
+                .unwrap_or(true);
+            if !retryable || attempt >= self.retries {
+                error!(file_id = %file_id, offset = offset, received = data.len(), error = %err, "download failed");
+                if self.short_reads && !data.is_empty() {
+                    return Ok(data.freeze());
+                }
+                return Err(Error::ApiCallFailed);
+            }
+            attempt += 1;
+            warn!(file_id = %file_id, attempt = attempt, received = data.len(), error = %err, "download failed, retrying");
+            thread::sleep(DOWNLOAD_RETRY_DELAY * (1 << (attempt - 1)));
+        }
+    }
+
+    fn download_range(&self, file_id: &str, offset: u64, size: usize) -> anyhow::Result<Bytes> {
+        let download_url = self.download_url(file_id)?;
+        match self.drive.download_ranges(&download_url, offset, size, self.connections) {
+            Err(err) if matches!(err.downcast_ref(), Some(DownloadError::LinkExpired(_))) => {
+                debug!(file_id = %file_id, error = %err, "refresh download url");
+                self.urls.invalidate(file_id);
+                let download_url = self.download_url(file_id)?;
+                self.drive
+                    .download_ranges(&download_url, offset, size, self.connections)
+            }
+            res => res,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileCacheConfig {
+    pub read_buffer_size: usize,
+    /// Number of blocks prefetched ahead of sequential reads
+    pub read_ahead: usize,
+    /// Maximum number of cached download links
+    pub url_cache_size: u64,
+    /// Time-to-live of cached download links in seconds
+    pub url_cache_ttl: u64,
+    /// Maximum size of the blocks kept in memory
+    pub memory_cache_size: u64,
+    /// Number of parallel ranged requests a block is downloaded with
+    pub download_connections: usize,
+    /// Number of times a failed download is retried
+    pub download_retries: u32,
+    /// Return the data received so far instead of an error when a download
+    /// keeps failing
+    pub short_reads: bool,
+}
+
+/// Reads files in `read_buffer_size` blocks, cached in memory and
+/// optionally on disk, shared by all file handles
+pub struct FileCache<D: DriveBackend> {
+    downloader: Arc<Downloader<D>>,
+    // number of blocks prefetched ahead of sequential reads
+    read_ahead: u64,
     // file handle -> cached file
-    cache: BTreeMap<u64, CachedFile>,
+    cache: Mutex<BTreeMap<u64, CachedFile>>,
+    prefetch_pool: ThreadPool,
 }
 
-impl FileCache {
-    pub fn new(drive: PikpakDrive, read_buffer_size: usize) -> Self {
+impl<D: DriveBackend> FileCache<D> {
+    pub fn new(drive: D, config: FileCacheConfig, disk_cache: Option<DiskCache>) -> Self {
+        let urls = CacheBuilder::new(config.url_cache_size)
+            .time_to_live(Duration::from_secs(config.url_cache_ttl))
+            .build();
+        let blocks = CacheBuilder::new(config.memory_cache_size)
+            .weigher(|_key, data: &Bytes| data.len().try_into().unwrap_or(u32::MAX))
+            .build();
         Self {
-            drive,
-            read_buffer_size,
-            cache: BTreeMap::new(),
+            downloader: Arc::new(Downloader {
+                drive,
+                block_size: config.read_buffer_size.max(1) as u64,
+                connections: config.download_connections,
+                retries: config.download_retries,
+                short_reads: config.short_reads,
+                urls,
+                blocks,
+                in_flight: Mutex::new(HashMap::new()),
+                disk_cache,
+            }),
+            read_ahead: config.read_ahead as u64,
+            cache: Mutex::new(BTreeMap::new()),
+            prefetch_pool: ThreadPool::new(config.read_ahead),
         }
     }
 
-    fn read_chunk(&self, file_id: &str, file_size: u64, offset: i64) -> Result<Bytes, Error> {
-        let size = std::cmp::min(
-            self.read_buffer_size,
-            file_size.saturating_sub(offset as u64) as usize,
-        );
-        let download_url = self
-            .drive
-            .get_download_url(file_id)
-            .map_err(|_| Error::ApiCallFailed)?;
-        let data = self
-            .drive
-            .download(&download_url, offset as _, size)
-            .map_err(|_| Error::ApiCallFailed)?;
-        Ok(data)
+    /// Download block `index` in the background, the download is skipped if
+    /// the file handle was released by the time it starts
+    fn prefetch(&self, file: Weak<FileInfo>, index: u64) {
+        let downloader = self.downloader.clone();
+        self.prefetch_pool.execute(move || {
+            if let Some(file) = file.upgrade() {
+                debug!(file_id = %file.id, block = index, "prefetch block");
+                if let Err(err) = downloader.get_block(&file, index) {
+                    warn!(file_id = %file.id, block = index, error = ?err, "prefetch failed");
+                }
+            }
+        });
     }
 
-    pub fn read(&mut self, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
-        let cached = self.cache.get(&fh).ok_or(Error::NoEntry)?;
-        let start_pos = cached.start_pos;
-        let end_pos = offset + i64::from(size);
-        let buf_size = cached.buffer.len();
+    pub fn read(&self, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+        let offset = offset as u64;
+        let block_size = self.downloader.block_size;
+        let mut cache = self.cache.lock();
+        let cached = cache.get_mut(&fh).ok_or(Error::NoEntry)?;
+        let end_pos = std::cmp::min(offset + u64::from(size), cached.file.size);
+        if offset >= end_pos {
+            return Ok(Bytes::new());
+        }
+        let first = offset / block_size;
+        let last = (end_pos - 1) / block_size;
+        let sequential = matches!(cached.last_block, Some(b) if first == b || first == b + 1);
+        cached.sequential_reads = if sequential { cached.sequential_reads + 1 } else { 0 };
+        cached.last_block = Some(last);
         debug!(
             fh = fh,
             offset = offset,
             size = size,
-            buffer_start = start_pos,
-            buffer_size = buf_size,
+            block = first,
+            sequential_reads = cached.sequential_reads,
             "read file cache"
         );
-        if offset >= start_pos && end_pos <= start_pos + buf_size as i64 {
-            let buf_start = (offset - start_pos) as usize;
-            let buf_end = buf_start + size as usize;
-            let data = cached.buffer.slice(buf_start..buf_end);
-            return Ok(data);
+        if !sequential {
+            cached.prefetch_from = last + 1;
         }
-        let chunk = self.read_chunk(&cached.file_id, cached.file_size, offset)?;
-        let new_cached = CachedFile {
-            file_id: cached.file_id.clone(),
-            file_size: cached.file_size,
-            start_pos: offset,
-            buffer: chunk.clone(),
+        if cached.sequential_reads >= SEQUENTIAL_READS {
+            let last_index = (cached.file.size - 1) / block_size;
+            let window_end = std::cmp::min(last + self.read_ahead, last_index);
+            for index in std::cmp::max(cached.prefetch_from, last + 1)..=window_end {
+                self.prefetch(Arc::downgrade(&cached.file), index);
+            }
+            cached.prefetch_from = std::cmp::max(cached.prefetch_from, window_end + 1);
+        }
+        let file = cached.file.clone();
+        // don't hold the lock while downloading
+        drop(cache);
+
+        let mut data = BytesMut::new();
+        for index in first..=last {
+            let block_data = self.downloader.get_block(&file, index)?;
+            let block_start = index * block_size;
+            let start = std::cmp::min(offset.saturating_sub(block_start) as usize, block_data.len());
+            let end = std::cmp::min((end_pos - block_start) as usize, block_data.len());
+            let slice = block_data.slice(start..end);
+            if first == last {
+                return Ok(slice);
+            }
+            data.extend_from_slice(&slice);
+            // chunk size maybe less than size
+            if (block_data.len() as u64) < std::cmp::min(block_size, file.size - block_start) {
+                break;
+            }
+        }
+        Ok(data.freeze())
+    }
+
+    pub fn open(&self, fh: u64, file_id: String, file_size: u64, version: String) {
+        let file = CachedFile {
+            file: Arc::new(FileInfo {
+                id: file_id,
+                version,
+                size: file_size,
+            }),
+            last_block: None,
+            sequential_reads: 0,
+            prefetch_from: 0,
         };
-        self.cache.insert(fh, new_cached);
+        self.cache.lock().insert(fh, file);
+    }
+
+    /// Id of the file read through `fh`
+    pub fn file_id(&self, fh: u64) -> Option<String> {
+        self.cache.lock().get(&fh).map(|cached| cached.file.id.clone())
+    }
+
+    pub fn release(&self, fh: u64) {
+        self.cache.lock().remove(&fh);
+    }
+}
 
-        // chunk size maybe less than size
-        let size = if chunk.len() >= size as usize {
-            size as usize
-        } else {
-            chunk.len()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::memory::MemoryDrive;
+
+    fn config(read_buffer_size: usize, read_ahead: usize) -> FileCacheConfig {
+        FileCacheConfig {
+            read_buffer_size,
+            read_ahead,
+            url_cache_size: 10,
+            url_cache_ttl: 60,
+            memory_cache_size: 1024,
+            download_connections: 1,
+            download_retries: 0,
+            short_reads: false,
+        }
+    }
+
+    #[test]
+    fn sequential_reads_prefetch_ahead() {
+        let drive = MemoryDrive::new();
+        let file = drive.add_file("", "a", "0123456789abcdefghij");
+        let cache = FileCache::new(drive, config(4, 2), None);
+        cache.open(1, file.id.clone(), 20, file.content_version());
+        let prefetch_from = || cache.cache.lock()[&1].prefetch_from;
+
+        assert_eq!(cache.read(1, 0, 4).unwrap(), Bytes::from("0123"));
+        assert_eq!(cache.read(1, 4, 4).unwrap(), Bytes::from("4567"));
+        assert_eq!(prefetch_from(), 1);
+        // the third read in a row fetches the next blocks in the background
+        assert_eq!(cache.read(1, 8, 4).unwrap(), Bytes::from("89ab"));
+        assert_eq!(prefetch_from(), 5);
+        let last = BlockKey {
+            file_id: file.id.clone(),
+            version: file.content_version(),
+            index: 4,
         };
-        Ok(chunk.slice(..size as usize))
+        for _ in 0..1000 {
+            if cache.downloader.blocks.contains_key(&last) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(cache.downloader.blocks.contains_key(&last));
+        assert_eq!(cache.read(1, 10, 8).unwrap(), Bytes::from("abcdefgh"));
+        assert_eq!(cache.read(1, 18, 10).unwrap(), Bytes::from("ij"));
+        assert!(cache.read(1, 20, 10).unwrap().is_empty());
+
+        // a jump back starts over
+        assert_eq!(cache.read(1, 1, 2).unwrap(), Bytes::from("12"));
+        assert_eq!(cache.cache.lock()[&1].sequential_reads, 0);
+        assert_eq!(prefetch_from(), 1);
     }
 
-    pub fn open(&mut self, fh: u64, file_id: String, file_size: u64) {
-        let file = CachedFile {
-            file_id,
-            file_size,
-            start_pos: 0,
-            buffer: Bytes::new(),
+    #[test]
+    fn failed_downloads_are_retried() {
+        let drive = MemoryDrive::new();
+        let file = drive.add_file("", "a", "0123456789");
+        let config = FileCacheConfig {
+            download_retries: 1,
+            ..config(4, 0)
+        };
+        let cache = FileCache::new(drive.clone(), config, None);
+        cache.open(1, file.id.clone(), 10, file.content_version());
+
+        drive.fail("download", 1);
+        assert_eq!(cache.read(1, 0, 4).unwrap(), Bytes::from("0123"));
+        drive.fail("download", 2);
+        assert!(cache.read(1, 4, 4).is_err());
+        // the failed block isn't kept
+        assert_eq!(cache.read(1, 4, 4).unwrap(), Bytes::from("4567"));
+    }
+
+    #[test]
+    fn handles_share_blocks() {
+        let drive = MemoryDrive::new();
+        let file = drive.add_file("", "a", "0123456789");
+        let cache = FileCache::new(drive.clone(), config(4, 0), None);
+        cache.open(1, file.id.clone(), 10, file.content_version());
+        assert_eq!(cache.read(1, 0, 10).unwrap(), Bytes::from("0123456789"));
+
+        // served from memory once the file is gone from the drive
+        drive.remove_file(&file.id).unwrap();
+        cache.open(2, file.id.clone(), 10, file.content_version());
+        assert_eq!(cache.read(2, 2, 6).unwrap(), Bytes::from("234567"));
+        // but only for the same content
+        cache.open(3, file.id.clone(), 10, "changed".to_string());
+        assert!(cache.read(3, 0, 4).is_err());
+    }
+
+    #[test]
+    fn download_links_are_reused_until_they_expire() {
+        let drive = MemoryDrive::new();
+        let file = drive.add_file("", "a", "hello");
+        let cache = FileCache::new(drive, config(4, 0), None);
+        let downloader = &cache.downloader;
+        let cached = |expires_at: Option<SystemTime>| {
+            let url = DownloadUrl {
+                url: "memory://cached".to_string(),
+                expires_at,
+            };
+            downloader.urls.insert(file.id.clone(), url);
         };
-        self.cache.insert(fh, file);
+        let fresh = format!("memory://{}", file.id);
+
+        cached(None);
+        assert_eq!(downloader.download_url(&file.id).unwrap(), "memory://cached");
+        cached(Some(SystemTime::now() + Duration::from_secs(3600)));
+        assert_eq!(downloader.download_url(&file.id).unwrap(), "memory://cached");
+        // about to expire
+        cached(Some(SystemTime::now() + Duration::from_secs(30)));
+        assert_eq!(downloader.download_url(&file.id).unwrap(), fresh);
+        assert_eq!(downloader.urls.get(&file.id).unwrap().url, fresh);
     }
 
-    pub fn release(&mut self, fh: u64) {
-        self.cache.remove(&fh);
+    #[test]
+    fn blocks_are_kept_on_disk() {
+        let dir = std::env::temp_dir().join(format!("pikpak-fuse-test-{}-block-cache", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let drive = MemoryDrive::new();
+        let file = drive.add_file("", "a", "0123456789");
+        let version = file.content_version();
+        let open = || {
+            let disk_cache = DiskCache::new(dir.clone(), 100, 4).unwrap();
+            let cache = FileCache::new(drive.clone(), config(4, 0), Some(disk_cache));
+            cache.open(1, file.id.clone(), 10, version.clone());
+            cache
+        };
+        assert_eq!(open().read(1, 0, 10).unwrap(), Bytes::from("0123456789"));
+
+        // later mounts read from disk, even once the file is gone from the drive
+        drive.remove_file(&file.id).unwrap();
+        assert_eq!(open().read(1, 0, 10).unwrap(), Bytes::from("0123456789"));
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
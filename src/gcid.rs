@@ -0,0 +1,155 @@
+//! PikPak content hash
+//!
+//! The hash sent to `create_file_with_proof` is a GCID: the file is split into
+//! blocks whose size depends on the file size, every block is hashed with
+//! SHA-1 and the hex encoded SHA-1 of the concatenated block digests is the
+//! content hash.
+use sha1::{Digest, Sha1};
+
+const MIN_BLOCK_SIZE: u64 = 0x40000;
+const MAX_BLOCK_SIZE: u64 = 0x200000;
+const MAX_BLOCKS: u64 = 0x200;
+
+/// Block size used for a file of `size` bytes
+pub fn block_size(size: u64) -> u64 {
+    let mut block_size = MIN_BLOCK_SIZE;
+    while size / block_size > MAX_BLOCKS && block_size < MAX_BLOCK_SIZE {
+        block_size <<= 1;
+    }
+    block_size
+}
+
+#[derive(Debug, Clone)]
+struct BlockHasher {
+    block_size: u64,
+    block: Sha1,
+    block_len: u64,
+    digests: Sha1,
+}
+
+impl BlockHasher {
+    fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            block: Sha1::default(),
+            block_len: 0,
+            digests: Sha1::default(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = std::cmp::min((self.block_size - self.block_len) as usize, data.len());
+            self.block.update(&data[..n]);
+            self.block_len += n as u64;
+            data = &data[n..];
+            if self.block_len == self.block_size {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let block = std::mem::take(&mut self.block);
+        self.digests.update(block.finalize());
+        self.block_len = 0;
+    }
+
+    fn finalize(mut self) -> String {
+        if self.block_len > 0 {
+            self.finish_block();
+        }
+        format!("{:X}", self.digests.finalize())
+    }
+}
+
+/// Incremental GCID hasher
+///
+/// The block size is only known once the final size is, so data is hashed
+/// with every possible block size at once and the matching one is picked in
+/// `finalize`.
+#[derive(Debug, Clone)]
+pub struct GcidHasher {
+    hashers: Vec<BlockHasher>,
+    size: u64,
+}
+
+impl Default for GcidHasher {
+    fn default() -> Self {
+        let mut hashers = Vec::new();
+        let mut block_size = MIN_BLOCK_SIZE;
+        while block_size <= MAX_BLOCK_SIZE {
+            hashers.push(BlockHasher::new(block_size));
+            block_size <<= 1;
+        }
+        Self { hashers, size: 0 }
+    }
+}
+
+impl GcidHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        for hasher in self.hashers.iter_mut() {
+            hasher.update(data);
+        }
+        self.size += data.len() as u64;
+    }
+
+    pub fn finalize(self) -> String {
+        let block_size = block_size(self.size);
+        self.hashers
+            .into_iter()
+            .find(|hasher| hasher.block_size == block_size)
+            .map(BlockHasher::finalize)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gcid(data: &[u8]) -> String {
+        let mut hasher = GcidHasher::default();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn content(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn block_size_boundaries() {
+        assert_eq!(block_size(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size(513 * MIN_BLOCK_SIZE - 1), MIN_BLOCK_SIZE);
+        assert_eq!(block_size(513 * MIN_BLOCK_SIZE), 2 * MIN_BLOCK_SIZE);
+        assert_eq!(block_size(513 * 2 * MIN_BLOCK_SIZE - 1), 2 * MIN_BLOCK_SIZE);
+        assert_eq!(block_size(513 * 2 * MIN_BLOCK_SIZE), 4 * MIN_BLOCK_SIZE);
+        assert_eq!(block_size(513 * MAX_BLOCK_SIZE), MAX_BLOCK_SIZE);
+        assert_eq!(block_size(u64::MAX), MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn known_digests() {
+        // no blocks, SHA-1 of nothing
+        assert_eq!(gcid(b""), "DA39A3EE5E6B4B0D3255BFEF95601890AFD80709");
+        assert_eq!(gcid(b"hello world"), "67BECF85308ACF0261750DA1075681EE5C412F05");
+        // two full blocks
+        let data = content(2 * MIN_BLOCK_SIZE as usize);
+        assert_eq!(gcid(&data), "8271A9EFAEBD4A042BDE8223242AD4FD1F5911C7");
+        // two full blocks and a short one
+        let data = content(2 * MIN_BLOCK_SIZE as usize + 1000);
+        assert_eq!(gcid(&data), "7A26211D5621A28C114568A5CACF0245A11BF45D");
+    }
+
+    #[test]
+    fn chunked_updates_match() {
+        let data = content(2 * MIN_BLOCK_SIZE as usize + 1000);
+        let mut hasher = GcidHasher::default();
+        // chunks straddling the block boundaries
+        for chunk in data.chunks(4099) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), gcid(&data));
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use fuser::FUSE_ROOT_ID;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+const INODES_FILE: &str = "inodes.json";
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+/// Forgotten inodes kept bound to their file id
+const MAX_FORGOTTEN: usize = 100_000;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PersistedInodes {
+    next_inode: u64,
+    // file id -> inode
+    inodes: HashMap<String, u64>,
+}
+
+/// Maps PikPak file ids to inode numbers that stay stable for the life of
+/// the mount, and across remounts when a working directory is given.
+///
+/// Only the files seen by the current mount are persisted, the ids of files
+/// deleted or moved away elsewhere are dropped on the next save.
+///
+/// A file the kernel forgot keeps its inode number so it gets the same one
+/// when it's listed again, but only for the last `MAX_FORGOTTEN` forgotten
+/// files, older ones get a new number the next time they're seen.
+#[derive(Debug)]
+pub struct InodeTable {
+    // file id -> inode
+    by_id: HashMap<String, u64>,
+    // inode -> file id
+    by_ino: HashMap<u64, String>,
+    // inode -> kernel lookup count
+    lookups: HashMap<u64, u64>,
+    // persisted file ids not seen since they were loaded
+    unseen: HashSet<String>,
+    // inode -> sequence number of forgotten inodes, and the reverse to find
+    // the oldest
+    forgotten: HashMap<u64, u64>,
+    forgotten_order: BTreeMap<u64, u64>,
+    forgotten_seq: u64,
+    next_inode: u64,
+    path: Option<PathBuf>,
+    dirty: bool,
+    last_persist: Instant,
+}
+
+impl InodeTable {
+    pub fn new(workdir: Option<PathBuf>) -> Self {
+        let path = workdir.map(|dir| dir.join(INODES_FILE));
+        let persisted = path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| match serde_json::from_slice::<PersistedInodes>(&data) {
+                Ok(persisted) => Some(persisted),
+                Err(err) => {
+                    error!(error = %err, "load persisted inodes failed");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        let mut table = Self {
+            by_id: HashMap::new(),
+            by_ino: HashMap::new(),
+            lookups: HashMap::new(),
+            unseen: HashSet::new(),
+            forgotten: HashMap::new(),
+            forgotten_order: BTreeMap::new(),
+            forgotten_seq: 0,
+            next_inode: persisted.next_inode.max(FUSE_ROOT_ID),
+            path,
+            dirty: false,
+            last_persist: Instant::now(),
+        };
+        for (id, ino) in persisted.inodes {
+            table.next_inode = table.next_inode.max(ino);
+            table.bind(ino, id);
+        }
+        debug!(inodes = table.by_id.len(), "inode table loaded");
+        table.unseen = table.by_id.keys().cloned().collect();
+        // root folder id is empty
+        table.bind(FUSE_ROOT_ID, String::new());
+        // saved on unmount at the latest so ids unseen for a whole mount are dropped
+        table.dirty = !table.unseen.is_empty();
+        table
+    }
+
+    /// Allocate an inode that isn't bound to a file id yet, e.g. for files
+    /// still being uploaded
+    pub fn allocate(&mut self) -> u64 {
+        self.next_inode = self.next_inode.wrapping_add(1);
+        self.dirty = true;
+        self.next_inode
+    }
+
+    /// Inode of `file_id`, allocating a new one on first sight
+    pub fn get_or_insert(&mut self, file_id: &str) -> u64 {
+        if let Some(&ino) = self.by_id.get(file_id) {
+            self.unseen.remove(file_id);
+            self.unforget(ino);
+            return ino;
+        }
+        let ino = self.allocate();
+        self.bind(ino, file_id.to_string());
+        ino
+    }
+
+    /// Associate `ino` with `file_id`
+    pub fn bind(&mut self, ino: u64, file_id: String) {
+        if let Some(old_id) = self.by_ino.insert(ino, file_id.clone()) {
+            self.by_id.remove(&old_id);
+        }
+        self.unseen.remove(&file_id);
+        self.by_id.insert(file_id, ino);
+        self.unforget(ino);
+        self.dirty = true;
+    }
+
+    /// Record that the kernel forgot `ino` and the file was dropped from
+    /// memory, the oldest forgotten inodes are unbound past `MAX_FORGOTTEN`
+    pub fn evict(&mut self, ino: u64) {
+        self.unforget(ino);
+        self.forgotten_seq += 1;
+        self.forgotten.insert(ino, self.forgotten_seq);
+        self.forgotten_order.insert(self.forgotten_seq, ino);
+        while self.forgotten.len() > MAX_FORGOTTEN {
+            match self.forgotten_order.pop_first() {
+                Some((_, oldest)) => self.remove(oldest),
+                None => break,
+            }
+        }
+    }
+
+    fn unforget(&mut self, ino: u64) {
+        if let Some(seq) = self.forgotten.remove(&ino) {
+            self.forgotten_order.remove(&seq);
+        }
+    }
+
+    /// Increase the kernel lookup count of `ino`
+    pub fn lookup(&mut self, ino: u64) {
+        self.unforget(ino);
+        *self.lookups.entry(ino).or_default() += 1;
+    }
+
+    /// Decrease the kernel lookup count of `ino`, returns the remaining count
+    pub fn forget(&mut self, ino: u64, nlookup: u64) -> u64 {
+        let count = self.lookups.entry(ino).or_default();
+        *count = count.saturating_sub(nlookup);
+        let count = *count;
+        if count == 0 {
+            self.lookups.remove(&ino);
+        }
+        count
+    }
+
+    pub fn lookup_count(&self, ino: u64) -> u64 {
+        self.lookups.get(&ino).copied().unwrap_or(0)
+    }
+
+    pub fn remove(&mut self, ino: u64) {
+        if ino == FUSE_ROOT_ID {
+            return;
+        }
+        if let Some(id) = self.by_ino.remove(&ino) {
+            self.by_id.remove(&id);
+            self.unseen.remove(&id);
+            self.dirty = true;
+        }
+        self.lookups.remove(&ino);
+        self.unforget(ino);
+    }
+
+    /// Persist the table if it changed and the last save is old enough
+    pub fn maybe_persist(&mut self) {
+        if self.dirty && self.last_persist.elapsed() >= PERSIST_INTERVAL {
+            self.persist();
+        }
+    }
+
+    pub fn persist(&mut self) {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+        if !self.dirty {
+            return;
+        }
+        let inodes = self
+            .by_id
+            .iter()
+            .filter(|(id, _)| !self.unseen.contains(*id))
+            .map(|(id, ino)| (id.clone(), *ino))
+            .collect();
+        let persisted = PersistedInodes {
+            next_inode: self.next_inode,
+            inodes,
+        };
+        let res = serde_json::to_vec(&persisted)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let tmp_path = path.with_extension("json.tmp");
+                fs::write(&tmp_path, data)?;
+                fs::rename(&tmp_path, path)?;
+                Ok(())
+            });
+        match res {
+            Ok(_) => {
+                debug!(inodes = self.by_id.len() - self.unseen.len(), "inode table persisted");
+                self.dirty = false;
+            }
+            Err(err) => error!(error = %err, "persist inode table failed"),
+        }
+        self.last_persist = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unseen_ids_are_pruned() {
+        let dir = std::env::temp_dir().join(format!("pikpak-fuse-test-{}-inodes", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut table = InodeTable::new(Some(dir.clone()));
+        let a = table.get_or_insert("a");
+        let b = table.get_or_insert("b");
+        table.persist();
+
+        // only `a` is seen by the next mount
+        let mut table = InodeTable::new(Some(dir.clone()));
+        assert_eq!(table.get_or_insert("a"), a);
+        table.persist();
+
+        let mut table = InodeTable::new(Some(dir.clone()));
+        assert_eq!(table.get_or_insert("a"), a);
+        assert_ne!(table.get_or_insert("b"), b);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn forgotten_inodes_are_capped() {
+        let mut table = InodeTable::new(None);
+        let first = table.get_or_insert("first");
+        table.evict(first);
+        let kept = table.get_or_insert("kept");
+        table.evict(kept);
+        // seen again, it's no longer forgotten
+        assert_eq!(table.get_or_insert("kept"), kept);
+        for i in 0..MAX_FORGOTTEN {
+            let ino = table.get_or_insert(&i.to_string());
+            table.evict(ino);
+        }
+        assert_eq!(table.forgotten.len(), MAX_FORGOTTEN);
+        assert_eq!(table.by_id.len(), MAX_FORGOTTEN + 2);
+        assert_eq!(table.get_or_insert("kept"), kept);
+        assert_ne!(table.get_or_insert("first"), first);
+        assert!(table.by_id.contains_key("0"));
+    }
+}
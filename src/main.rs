@@ -1,17 +1,23 @@
-use std::{env, io, path::PathBuf};
+use std::{env, io, path::PathBuf, time::Duration};
 
 use clap::Parser;
 use fuser::MountOption;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
-use drive::{model::Credentials,PikpakDrive, DriveConfig};
-use vfs::PikpakDriveFileSystem;
+use drive::{model::Credentials,PikpakDrive, DriveConfig, RateSchedule};
+use vfs::{PikpakDriveFileSystem, VfsConfig};
 
 
 
 
+mod disk_cache;
 mod drive;
 mod error;
 mod file_cache;
+mod gcid;
+mod inode_table;
+mod staging;
+mod thread_pool;
+mod upload_queue;
 mod vfs;
 mod cache;
 
@@ -31,9 +37,15 @@ struct Opt {
     #[structopt(long, env = "PROXY_URL", default_value = "")]
     proxy_url: String,
 
-    /// Working directory, refresh_token will be stored in there if specified
+    /// Working directory, refresh_token, inode numbers and the upload queue will be stored in there if specified
     #[clap(short = 'w', long)]
     workdir: Option<PathBuf>,
+    /// Directory for files being written and downloaded blocks, files being written default to the working directory
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+    /// Maximum size of the downloaded blocks kept in the cache directory in bytes, 0 to disable
+    #[clap(long, default_value = "1073741824")]
+    cache_max_size: u64,
     /// pikpak PDS domain id
     #[clap(long)]
     domain_id: Option<String>,
@@ -44,9 +56,85 @@ struct Opt {
     #[clap(short = 'S', long, default_value = "10485760")]
     read_buffer_size: usize,
 
+    /// Number of read buffers prefetched ahead of sequential reads, 0 to disable
+    #[clap(long, default_value = "2")]
+    read_ahead: usize,
+
+    /// Number of parallel ranged requests a read buffer is downloaded with
+    #[clap(long, default_value = "1")]
+    download_connections: usize,
+
+    /// Number of times a failed download is retried, resuming after the bytes already received
+    #[clap(long, default_value = "3")]
+    download_retries: u32,
+
+    /// Reply to reads with the data received so far instead of an I/O error when a download keeps failing
+    #[clap(long)]
+    short_reads: bool,
+
+    /// Maximum size of the downloaded blocks kept in memory in bytes, defaults to 256MB
+    #[clap(long, default_value = "268435456")]
+    memory_cache_size: u64,
+
+    /// Maximum number of cached download links
+    #[clap(long, default_value = "1000")]
+    url_cache_size: u64,
+
+    /// Download link cache time-to-live in seconds, links expiring sooner are refreshed earlier
+    #[clap(long, default_value = "600")]
+    url_cache_ttl: u64,
+
     /// Upload buffer size in bytes, defaults to 16MB
     #[clap(long, default_value = "16777216")]
     upload_buffer_size: usize,
+
+    /// Directory listing cache time-to-live in seconds
+    #[clap(long, default_value = "60")]
+    dir_cache_ttl: u64,
+
+    /// Maximum number of cached directory listings
+    #[clap(long, default_value = "1000")]
+    dir_cache_size: u64,
+
+    /// How long the kernel may cache file attributes, in seconds
+    #[clap(long, default_value = "1", parse(try_from_str = parse_timeout))]
+    attr_timeout: Duration,
+
+    /// How long the kernel may cache file names, in seconds
+    #[clap(long, default_value = "1", parse(try_from_str = parse_timeout))]
+    entry_timeout: Duration,
+
+    /// How long nonexistent file names are cached, in seconds, 0 to disable
+    #[clap(long, default_value = "5", parse(try_from_str = parse_timeout))]
+    negative_timeout: Duration,
+
+    /// Number of parts of a file uploaded at the same time
+    #[clap(long, default_value = "4")]
+    upload_concurrency: usize,
+
+    /// Keep the mode and owner set by chmod and chown in memory instead of ignoring them
+    #[clap(long)]
+    store_permissions: bool,
+
+    /// Download bandwidth limit in bytes per second with an optional K/M/G suffix, 0 for unlimited,
+    /// rates prefixed with a local time range only apply then, e.g. `08:00-23:00=1M,4M`
+    #[clap(long, default_value = "0")]
+    max_download_rate: RateSchedule,
+
+    /// Upload bandwidth limit, same format as `--max-download-rate`
+    #[clap(long, default_value = "0")]
+    max_upload_rate: RateSchedule,
+
+    /// Number of threads handling filesystem requests
+    #[clap(long, default_value = "16")]
+    worker_threads: usize,
+}
+
+/// Parse a timeout in seconds, fractions allowed
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|err: std::num::ParseFloatError| err.to_string())?;
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| format!("invalid timeout {}, expected a non-negative number of seconds", s))
 }
 
 fn main() -> anyhow::Result<()> {
@@ -62,17 +150,47 @@ fn main() -> anyhow::Result<()> {
     }
 
     let opt = Opt::parse();
+    let vfs_config = VfsConfig {
+        workdir: opt.workdir.clone(),
+        cache_dir: opt.cache_dir,
+        cache_max_size: opt.cache_max_size,
+        read_buffer_size: opt.read_buffer_size,
+        read_ahead: opt.read_ahead,
+        download_connections: opt.download_connections,
+        download_retries: opt.download_retries,
+        short_reads: opt.short_reads,
+        memory_cache_size: opt.memory_cache_size,
+        url_cache_size: opt.url_cache_size,
+        url_cache_ttl: opt.url_cache_ttl,
+        upload_buffer_size: opt.upload_buffer_size,
+        upload_concurrency: opt.upload_concurrency,
+        dir_cache_ttl: opt.dir_cache_ttl,
+        dir_cache_size: opt.dir_cache_size,
+        attr_timeout: opt.attr_timeout,
+        entry_timeout: opt.entry_timeout,
+        negative_timeout: opt.negative_timeout,
+        worker_threads: opt.worker_threads,
+        store_permissions: opt.store_permissions,
+    };
     let drive_config = if opt.proxy_url.is_empty() {
         DriveConfig {
             api_base_url: "https://api-drive.mypikpak.com/drive/v1/files".to_string(),
             refresh_token_url: "https://user.mypikpak.com/v1/auth/signin".to_string(),
             workdir: opt.workdir,
+            api_host: Some("api-drive.mypikpak.com".to_string()),
+            oss_endpoint: None,
+            max_download_rate: opt.max_download_rate.clone(),
+            max_upload_rate: opt.max_upload_rate.clone(),
         }
     } else {
         DriveConfig {
             api_base_url: format!("{}/https://api-drive.mypikpak.com/drive/v1/files", opt.proxy_url),
             refresh_token_url: format!("{}/https://user.mypikpak.com/v1/auth/signin",opt.proxy_url),
             workdir: opt.workdir,
+            api_host: Some("api-drive.mypikpak.com".to_string()),
+            oss_endpoint: None,
+            max_download_rate: opt.max_download_rate.clone(),
+            max_upload_rate: opt.max_upload_rate.clone(),
         }
     };
 
@@ -89,7 +207,7 @@ fn main() -> anyhow::Result<()> {
     })?;
 
     let _nick_name = drive.nick_name.clone();
-    let vfs = PikpakDriveFileSystem::new(drive, opt.read_buffer_size,opt.upload_buffer_size);
+    let vfs = PikpakDriveFileSystem::new(drive, vfs_config);
     let mut mount_options = vec![MountOption::AutoUnmount, MountOption::NoAtime];
     // read only for now
     // mount_options.push(MountOption::RO);
@@ -0,0 +1,120 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, error};
+
+use crate::gcid::GcidHasher;
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Local file holding the data written to a file handle until it's uploaded
+#[derive(Debug)]
+pub struct StagingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // files still being written are temporary, queued ones are owned by the
+    // upload journal
+    remove_on_drop: bool,
+}
+
+impl StagingFile {
+    pub fn create(dir: &Path, name: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        debug!(path = %path.display(), "staging file created");
+        Ok(Self {
+            path,
+            file,
+            size: 0,
+            remove_on_drop: true,
+        })
+    }
+
+    /// Open a file previously moved with `persist`, it's kept when dropped
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            remove_on_drop: false,
+        })
+    }
+
+    /// Durably move the data to `dest`
+    pub fn persist(mut self, dest: &Path) -> io::Result<StagingFile> {
+        self.file.sync_all()?;
+        if let Some(dir) = dest.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if fs::rename(&self.path, dest).is_ok() {
+            self.remove_on_drop = false;
+        } else {
+            // staging and upload directories may be on different filesystems
+            let tmp_path = dest.with_extension("tmp");
+            fs::copy(&self.path, &tmp_path)?;
+            File::open(&tmp_path)?.sync_all()?;
+            fs::rename(&tmp_path, dest)?;
+        }
+        Self::open(dest)
+    }
+
+    /// Write `data` at `offset`, gaps left by out of order writes read as zeros
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, offset)?;
+        self.size = self.size.max(offset + data.len() as u64);
+        Ok(())
+    }
+
+    /// Truncate or extend the data to `size`, extended data reads as zeros
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)?;
+        self.size = size;
+        Ok(())
+    }
+
+    pub fn read_at(&self, offset: u64, size: usize) -> io::Result<Bytes> {
+        let size = std::cmp::min(size as u64, self.size.saturating_sub(offset)) as usize;
+        let mut buffer = BytesMut::zeroed(size);
+        self.file.read_exact_at(&mut buffer, offset)?;
+        Ok(buffer.freeze())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Content hash of everything written so far
+    pub fn hash(&self) -> io::Result<String> {
+        let mut hasher = GcidHasher::default();
+        let mut offset = 0;
+        while offset < self.size {
+            let data = self.read_at(offset, HASH_BUFFER_SIZE)?;
+            hasher.update(&data);
+            offset += data.len() as u64;
+        }
+        Ok(hasher.finalize())
+    }
+}
+
+impl Drop for StagingFile {
+    fn drop(&mut self) {
+        if !self.remove_on_drop {
+            return;
+        }
+        if let Err(err) = fs::remove_file(&self.path) {
+            error!(path = %self.path.display(), error = %err, "remove staging file failed");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use parking_lot::Mutex;
+use tracing::error;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed size pool of worker threads running FUSE requests
+#[derive(Clone)]
+pub struct ThreadPool {
+    sender: mpsc::Sender<Job>,
+    // key -> jobs waiting for the running job with the same key
+    queues: Arc<Mutex<HashMap<u64, VecDeque<Job>>>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..size.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("pikpak-fuse-worker-{}", i))
+                .spawn(move || loop {
+                    let job = receiver.lock().recv();
+                    match job {
+                        Ok(job) => {
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                error!("worker job panicked");
+                            }
+                        }
+                        // all senders dropped, the filesystem is gone
+                        Err(_) => break,
+                    }
+                })
+                .expect("spawn worker thread failed");
+        }
+        Self {
+            sender,
+            queues: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.sender.send(Box::new(f)).is_err() {
+            error!("worker pool is shut down");
+        }
+    }
+
+    /// Run `f` once every job previously submitted with the same `key` has
+    /// finished, e.g. to keep writes to one file handle in order
+    pub fn execute_ordered<F>(&self, key: u64, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut queues = self.queues.lock();
+        if let Some(queue) = queues.get_mut(&key) {
+            queue.push_back(Box::new(f));
+            return;
+        }
+        queues.insert(key, VecDeque::new());
+        drop(queues);
+        let pool = self.clone();
+        self.execute(move || pool.drain(key, Box::new(f)));
+    }
+
+    fn drain(&self, key: u64, mut job: Job) {
+        loop {
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!(key = key, "worker job panicked");
+            }
+            let mut queues = self.queues.lock();
+            match queues.get_mut(&key).and_then(|queue| queue.pop_front()) {
+                Some(next) => job = next,
+                None => {
+                    queues.remove(&key);
+                    return;
+                }
+            }
+        }
+    }
+}
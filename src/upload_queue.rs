@@ -0,0 +1,812 @@
+//! Background upload queue
+//!
+//! Closed files are moved into the queue directory together with a JSON
+//! journal describing the upload. Workers upload them in the background and
+//! update the journal after every step, so an interrupted upload resumes from
+//! the saved OSS `upload_id` and completed parts after a restart.
+use std::collections::BTreeMap;
+use std::any::Any;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+use quick_xml::se::Serializer as XmlSerializer;
+use quick_xml::Writer;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::drive::model::{CompleteMultipartUpload, OssArgs, PartInfo};
+use crate::drive::{DriveBackend, PikpakFile, UploadError};
+use crate::staging::StagingFile;
+
+const JOURNAL_EXTENSION: &str = "json";
+const DATA_EXTENSION: &str = "data";
+const STATUS_FILE: &str = "status.json";
+const UPLOAD_WORKERS: usize = 2;
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+/// Attempts failed with an error other than a network or server error after
+/// which an upload is given up
+const MAX_FAILURES: u32 = 5;
+/// OSS multipart upload limits
+const MAX_PARTS: u64 = 10_000;
+const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+const PART_SIZE_ALIGN: u64 = 1024 * 1024;
+/// Largest file a multipart upload can hold
+pub const MAX_UPLOAD_SIZE: u64 = MAX_PARTS * MAX_PART_SIZE;
+
+/// Journal of a queued upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadJob {
+    pub id: u64,
+    /// Inode of the file in the mount the job was queued from, meaningless
+    /// after a restart
+    pub ino: u64,
+    pub name: String,
+    pub parent_id: String,
+    pub size: u64,
+    pub part_size: u64,
+    pub hash: Option<String>,
+    /// File replaced by the upload, it's kept until the upload finished
+    #[serde(default)]
+    pub replaces: Option<String>,
+    /// File created by `create_file_with_proof`
+    pub file: Option<PikpakFile>,
+    pub oss_args: Option<OssArgs>,
+    pub upload_id: Option<String>,
+    /// Uploaded parts
+    pub parts: Vec<PartInfo>,
+    pub attempts: u32,
+    /// Attempts failed with an error retrying doesn't fix, e.g. the parent
+    /// folder is gone, the upload is given up after `MAX_FAILURES`
+    #[serde(default)]
+    pub failures: u32,
+}
+
+impl UploadJob {
+    fn part_count(&self) -> u64 {
+        self.size.div_ceil(self.part_size)
+    }
+
+    fn is_failed(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadPhase {
+    Queued,
+    Uploading,
+    Retrying,
+    /// Given up, the data is kept in the queue directory until the journal
+    /// and data files are removed
+    Failed,
+}
+
+/// Progress of a queued upload
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadStatus {
+    pub name: String,
+    pub parent_id: String,
+    pub size: u64,
+    pub phase: UploadPhase,
+    pub uploaded_parts: usize,
+    pub total_parts: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: UploadJob,
+    phase: UploadPhase,
+    not_before: Instant,
+    last_error: Option<String>,
+}
+
+type CompleteCallback = Box<dyn Fn(&UploadJob, &PikpakFile) + Send + Sync>;
+
+struct Shared<D: DriveBackend> {
+    drive: D,
+    dir: PathBuf,
+    entries: Mutex<BTreeMap<u64, Entry>>,
+    next_id: AtomicU64,
+    // parts of one file uploaded at the same time
+    concurrency: usize,
+    cond: Condvar,
+    on_complete: CompleteCallback,
+    stopped: AtomicBool,
+}
+
+/// Queue of files waiting to be uploaded
+#[derive(Clone)]
+pub struct UploadQueue<D: DriveBackend> {
+    shared: Arc<Shared<D>>,
+}
+
+impl<D: DriveBackend> UploadQueue<D> {
+    /// Load the journals left in `dir` and start the upload workers,
+    /// `on_complete` is called with every finished job and the uploaded file
+    pub fn new<F>(drive: D, dir: PathBuf, concurrency: usize, on_complete: F) -> Self
+    where
+        F: Fn(&UploadJob, &PikpakFile) + Send + Sync + 'static,
+    {
+        let mut entries = BTreeMap::new();
+        let mut next_id = 1;
+        for job in load_journals(&dir) {
+            next_id = next_id.max(job.id + 1);
+            let phase = if job.is_failed() {
+                warn!(name = %job.name, attempts = job.attempts, "upload failed before, not resuming");
+                UploadPhase::Failed
+            } else {
+                info!(name = %job.name, size = job.size, parts = job.parts.len(), "resuming upload");
+                UploadPhase::Queued
+            };
+            entries.insert(
+                job.id,
+                Entry {
+                    job,
+                    phase,
+                    not_before: Instant::now(),
+                    last_error: None,
+                },
+            );
+        }
+        let queue = Self {
+            shared: Arc::new(Shared {
+                drive,
+                dir,
+                entries: Mutex::new(entries),
+                next_id: AtomicU64::new(next_id),
+                concurrency: concurrency.max(1),
+                cond: Condvar::new(),
+                on_complete: Box::new(on_complete),
+                stopped: AtomicBool::new(false),
+            }),
+        };
+        queue.write_status();
+        for i in 0..UPLOAD_WORKERS {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("pikpak-fuse-upload-{}", i))
+                .spawn(move || queue.run())
+                .expect("spawn upload thread failed");
+        }
+        queue
+    }
+
+    /// Queue the data in `staging` to be uploaded as `name` into the folder
+    /// `parent_id`, in parts of at least `min_part_size` bytes, replacing the
+    /// file `replaces` once uploaded. Returns the id of the job.
+    pub fn enqueue(
+        &self,
+        staging: StagingFile,
+        ino: u64,
+        name: &str,
+        parent_id: &str,
+        replaces: Option<String>,
+        min_part_size: u64,
+    ) -> std::io::Result<u64> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let staging = staging.persist(&self.data_path(id))?;
+        let job = UploadJob {
+            id,
+            ino,
+            name: name.to_string(),
+            parent_id: parent_id.to_string(),
+            size: staging.size(),
+            part_size: part_size(staging.size(), min_part_size),
+            hash: None,
+            replaces,
+            file: None,
+            oss_args: None,
+            upload_id: None,
+            parts: Vec::new(),
+            attempts: 0,
+            failures: 0,
+        };
+        self.save_journal(&job)?;
+        info!(name = %job.name, size = job.size, "upload queued");
+        self.shared.entries.lock().insert(
+            id,
+            Entry {
+                job,
+                phase: UploadPhase::Queued,
+                not_before: Instant::now(),
+                last_error: None,
+            },
+        );
+        self.write_status();
+        self.shared.cond.notify_one();
+        Ok(id)
+    }
+
+    /// Read the data of the queued job `id`, `None` once it's uploaded
+    pub fn read(&self, id: u64, offset: u64, size: usize) -> std::io::Result<Option<Bytes>> {
+        match StagingFile::open(&self.data_path(id)) {
+            Ok(data) => data.read_at(offset, size).map(Some),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Stop the workers once their current upload is done, the jobs left
+    /// are resumed by the next queue loading the directory
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        let _entries = self.shared.entries.lock();
+        self.shared.cond.notify_all();
+    }
+
+    /// Status of every queued upload
+    pub fn status(&self) -> Vec<UploadStatus> {
+        self.shared
+            .entries
+            .lock()
+            .values()
+            .map(|entry| UploadStatus {
+                name: entry.job.name.clone(),
+                parent_id: entry.job.parent_id.clone(),
+                size: entry.job.size,
+                phase: entry.phase.clone(),
+                uploaded_parts: entry.job.parts.len(),
+                total_parts: entry.job.part_count(),
+                attempts: entry.job.attempts,
+                last_error: entry.last_error.clone(),
+            })
+            .collect()
+    }
+
+    fn run(&self) {
+        while let Some(mut job) = self.next_job() {
+            let res = match panic::catch_unwind(AssertUnwindSafe(|| self.process(&mut job))) {
+                Ok(res) => res,
+                Err(panic) => {
+                    // a bug, another attempt would panic again
+                    job.failures = MAX_FAILURES;
+                    Err(anyhow::anyhow!("upload panicked: {}", panic_message(&*panic)))
+                }
+            };
+            let restart = match &res {
+                Err(err) => err
+                    .downcast_ref::<UploadError>()
+                    .map(|err| !err.is_retryable())
+                    .unwrap_or(false),
+                Ok(_) => false,
+            };
+            if matches!(&res, Err(err) if !is_transient(err)) && !job.is_failed() {
+                job.failures += 1;
+            }
+            if restart {
+                self.restart(&mut job);
+            }
+            let mut entries = self.shared.entries.lock();
+            match res {
+                Ok(file) => {
+                    info!(name = %job.name, size = job.size, "upload finished");
+                    entries.remove(&job.id);
+                    drop(entries);
+                    // the data is kept until the file is bound, reads of
+                    // the pending file are served from it
+                    self.remove_file(&self.journal_path(job.id));
+                    let on_complete = AssertUnwindSafe(|| (self.shared.on_complete)(&job, &file));
+                    if let Err(panic) = panic::catch_unwind(on_complete) {
+                        error!(name = %job.name, "upload finished callback panicked: {}", panic_message(&*panic));
+                    }
+                    self.remove_file(&self.data_path(job.id));
+                }
+                Err(err) => {
+                    job.attempts += 1;
+                    let delay = retry_delay(job.attempts);
+                    let phase = if job.is_failed() {
+                        error!(
+                            name = %job.name,
+                            attempts = job.attempts,
+                            error = %err,
+                            "upload failed, giving up"
+                        );
+                        UploadPhase::Failed
+                    } else {
+                        warn!(
+                            name = %job.name,
+                            attempts = job.attempts,
+                            error = %err,
+                            "upload failed, retrying in {:?}",
+                            delay
+                        );
+                        UploadPhase::Retrying
+                    };
+                    if let Err(err) = self.save_journal(&job) {
+                        error!(name = %job.name, error = %err, "save upload journal failed");
+                    }
+                    if let Some(entry) = entries.get_mut(&job.id) {
+                        entry.job = job;
+                        entry.phase = phase;
+                        entry.not_before = Instant::now() + delay;
+                        entry.last_error = Some(err.to_string());
+                    }
+                    drop(entries);
+                }
+            }
+            self.write_status();
+        }
+    }
+
+    /// Abort the multipart upload of `job` so the next attempt starts over,
+    /// e.g. after the upload or its credentials expired
+    fn restart(&self, job: &mut UploadJob) {
+        if let (Some(oss_args), Some(upload_id)) = (job.oss_args.as_ref(), job.upload_id.as_ref()) {
+            info!(name = %job.name, upload_id = %upload_id, "aborting multipart upload");
+            if let Err(err) = self.shared.drive.abort_upload(oss_args, upload_id) {
+                warn!(name = %job.name, error = %err, "abort multipart upload failed");
+            }
+        }
+        job.file = None;
+        job.oss_args = None;
+        job.upload_id = None;
+        job.parts.clear();
+    }
+
+    /// Wait for a job that is ready to be uploaded, `None` once stopped
+    fn next_job(&self) -> Option<UploadJob> {
+        let mut entries = self.shared.entries.lock();
+        loop {
+            if self.shared.stopped.load(Ordering::SeqCst) {
+                return None;
+            }
+            let now = Instant::now();
+            let ready = entries
+                .values_mut()
+                .filter(|entry| !matches!(entry.phase, UploadPhase::Uploading | UploadPhase::Failed))
+                .min_by_key(|entry| entry.not_before);
+            match ready {
+                Some(entry) if entry.not_before <= now => {
+                    entry.phase = UploadPhase::Uploading;
+                    return Some(entry.job.clone());
+                }
+                Some(entry) => {
+                    let timeout = entry.not_before - now;
+                    self.shared.cond.wait_for(&mut entries, timeout);
+                }
+                None => self.shared.cond.wait(&mut entries),
+            }
+        }
+    }
+
+    fn process(&self, job: &mut UploadJob) -> anyhow::Result<PikpakFile> {
+        let drive = &self.shared.drive;
+        let data = StagingFile::open(&self.data_path(job.id))?;
+        let hash = match job.hash.clone() {
+            Some(hash) => hash,
+            None => {
+                let hash = data.hash()?;
+                job.hash = Some(hash.clone());
+                self.save_journal(job)?;
+                hash
+            }
+        };
+
+        let file = match job.file.clone() {
+            Some(file) => file,
+            None => {
+                debug!(name = %job.name, size = job.size, hash = %hash, "uploading");
+                let res = drive.create_file_with_proof(&job.name, &job.parent_id, &hash, job.size)?;
+                if res.is_instant() {
+                    info!(name = %job.name, size = job.size, "file already exists on server, upload skipped");
+                    return Ok(res.file);
+                }
+                let params = &res
+                    .resumable
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("missing resumable upload params"))?
+                    .params;
+                job.oss_args = Some(OssArgs {
+                    bucket: params.bucket.to_string(),
+                    key: params.key.to_string(),
+                    endpoint: params.endpoint.to_string(),
+                    access_key_id: params.access_key_id.to_string(),
+                    access_key_secret: params.access_key_secret.to_string(),
+                    security_token: params.security_token.to_string(),
+                });
+                job.file = Some(res.file.clone());
+                self.save_journal(job)?;
+                res.file
+            }
+        };
+        let oss_args = job
+            .oss_args
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("missing oss args"))?;
+
+        let upload_id = match job.upload_id.clone() {
+            Some(upload_id) => upload_id,
+            None => {
+                let upload_id = drive.get_pre_upload_info(&oss_args)?;
+                debug!(name = %job.name, upload_id = %upload_id, "pre upload info get upload_id success");
+                job.upload_id = Some(upload_id.clone());
+                self.save_journal(job)?;
+                upload_id
+            }
+        };
+
+        let part_numbers: Vec<u64> = (1..=job.part_count())
+            .filter(|part_number| !job.parts.iter().any(|part| part.PartNumber.PartNumber == *part_number))
+            .collect();
+        let part_size = job.part_size;
+        let progress = Mutex::new(&mut *job);
+        drive.upload_parts(
+            &file,
+            &oss_args,
+            &upload_id,
+            &part_numbers,
+            self.shared.concurrency,
+            |part_number| {
+                let offset = (part_number - 1) * part_size;
+                Ok(data.read_at(offset, part_size as usize)?)
+            },
+            |part| {
+                let mut job = progress.lock();
+                debug!(name = %job.name, part_number = part.PartNumber.PartNumber, "part uploaded");
+                job.parts.push(part);
+                // a part missing from the journal is uploaded again on resume
+                if let Err(err) = self.save_journal(&job) {
+                    error!(name = %job.name, error = %err, "save upload journal failed");
+                }
+                self.update_progress(&job);
+            },
+        )?;
+
+        let mut parts = job.parts.clone();
+        parts.sort_by_key(|part| part.PartNumber.PartNumber);
+        let upload_tags = CompleteMultipartUpload { Part: parts };
+        let mut buffer = Vec::new();
+        let mut ser = XmlSerializer::with_root(
+            Writer::new_with_indent(&mut buffer, b' ', 4),
+            Some("CompleteMultipartUpload"),
+        );
+        upload_tags.serialize(&mut ser)?;
+        drive.complete_upload(&file, String::from_utf8(buffer)?, &oss_args, &upload_id)?;
+        Ok(file)
+    }
+
+    fn update_progress(&self, job: &UploadJob) {
+        if let Some(entry) = self.shared.entries.lock().get_mut(&job.id) {
+            entry.job.parts = job.parts.clone();
+        }
+        self.write_status();
+    }
+
+    fn journal_path(&self, id: u64) -> PathBuf {
+        self.shared.dir.join(format!("{}.{}", id, JOURNAL_EXTENSION))
+    }
+
+    fn data_path(&self, id: u64) -> PathBuf {
+        self.shared.dir.join(format!("{}.{}", id, DATA_EXTENSION))
+    }
+
+    fn save_journal(&self, job: &UploadJob) -> std::io::Result<()> {
+        let path = self.journal_path(job.id);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(job)?)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    fn remove_file(&self, path: &Path) {
+        if let Err(err) = fs::remove_file(path) {
+            error!(path = %path.display(), error = %err, "remove upload file failed");
+        }
+    }
+
+    /// Write the queue status to `status.json` in the queue directory
+    fn write_status(&self) {
+        let path = self.shared.dir.join(STATUS_FILE);
+        let res = serde_json::to_vec_pretty(&self.status())
+            .map_err(std::io::Error::from)
+            .and_then(|data| {
+                fs::create_dir_all(&self.shared.dir)?;
+                let tmp_path = path.with_extension("tmp");
+                fs::write(&tmp_path, data)?;
+                fs::rename(&tmp_path, &path)
+            });
+        if let Err(err) = res {
+            error!(path = %path.display(), error = %err, "write upload status failed");
+        }
+    }
+}
+
+fn load_journals(dir: &Path) -> Vec<UploadJob> {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(_) => return Vec::new(),
+    };
+    let mut jobs = Vec::new();
+    for entry in read_dir.flatten() {
+        let path = entry.path();
+        let is_data = path.extension().map(|ext| ext == DATA_EXTENSION).unwrap_or(false);
+        if is_data && !path.with_extension(JOURNAL_EXTENSION).exists() {
+            // uploaded, the process stopped before it was removed
+            debug!(path = %path.display(), "removing uploaded data");
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        let is_journal = path.extension().map(|ext| ext == JOURNAL_EXTENSION).unwrap_or(false);
+        if !is_journal || path.file_name().map(|name| name == STATUS_FILE).unwrap_or(false) {
+            continue;
+        }
+        let job = fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice::<UploadJob>(&data)?));
+        match job {
+            Ok(job) if path.with_extension(DATA_EXTENSION).exists() => jobs.push(job),
+            Ok(job) => {
+                error!(name = %job.name, "upload data missing, dropping journal");
+                let _ = fs::remove_file(&path);
+            }
+            Err(err) => error!(path = %path.display(), error = %err, "load upload journal failed"),
+        }
+    }
+    jobs
+}
+
+/// Part size for a file of `size` bytes, grown beyond `min_part_size` when
+/// needed to stay within the OSS part count limit
+fn part_size(size: u64, min_part_size: u64) -> u64 {
+    let min_part_size = min_part_size.max(1);
+    if size.div_ceil(min_part_size) <= MAX_PARTS {
+        return min_part_size;
+    }
+    let part_size = size.div_ceil(MAX_PARTS).div_ceil(PART_SIZE_ALIGN) * PART_SIZE_ALIGN;
+    part_size.min(MAX_PART_SIZE)
+}
+
+/// Whether the error is a network error or an error reported by the server
+/// that may go away, anything else fails the same way every attempt
+fn is_transient(err: &anyhow::Error) -> bool {
+    if let Some(err) = err.downcast_ref::<UploadError>() {
+        return err.is_retryable();
+    }
+    err.downcast_ref::<reqwest::Error>()
+        .map(|err| err.status().map(|status| !status.is_client_error()).unwrap_or(true))
+        .unwrap_or(false)
+}
+
+/// Exponential backoff between attempts
+fn retry_delay(attempts: u32) -> Duration {
+    let delay = MIN_RETRY_DELAY.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    std::cmp::min(delay, MAX_RETRY_DELAY)
+}
+
+/// Message a panic was raised with
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::drive::memory::MemoryDrive;
+    use crate::gcid::GcidHasher;
+
+    const PART_SIZE: u64 = 4;
+
+    fn workdir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pikpak-fuse-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Poll `done` for up to 10 seconds
+    fn wait_for(what: &str, done: impl Fn() -> bool) {
+        for _ in 0..1000 {
+            if done() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    /// Journal and data of an upload of `data` that was interrupted after its
+    /// file was created on `drive`
+    fn interrupted(drive: &MemoryDrive, dir: &Path, data: &[u8]) -> UploadJob {
+        let mut hasher = GcidHasher::default();
+        hasher.update(data);
+        let hash = hasher.finalize();
+        let res = drive.create_file_with_proof("a", "", &hash, data.len() as u64).unwrap();
+        let params = &res.resumable.as_ref().unwrap().params;
+        let oss_args = OssArgs {
+            bucket: params.bucket.to_string(),
+            key: params.key.to_string(),
+            endpoint: params.endpoint.to_string(),
+            access_key_id: params.access_key_id.to_string(),
+            access_key_secret: params.access_key_secret.to_string(),
+            security_token: params.security_token.to_string(),
+        };
+        let job = UploadJob {
+            id: 1,
+            ino: 0,
+            name: "a".to_string(),
+            parent_id: String::new(),
+            size: data.len() as u64,
+            part_size: PART_SIZE,
+            hash: Some(hash),
+            replaces: None,
+            file: Some(res.file),
+            oss_args: Some(oss_args),
+            upload_id: None,
+            parts: Vec::new(),
+            attempts: 0,
+            failures: 0,
+        };
+        fs::write(dir.join(format!("{}.{}", job.id, DATA_EXTENSION)), data).unwrap();
+        job
+    }
+
+    fn save(dir: &Path, job: &UploadJob) {
+        let path = dir.join(format!("{}.{}", job.id, JOURNAL_EXTENSION));
+        fs::write(path, serde_json::to_vec(job).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn part_size_stays_within_oss_limits() {
+        const MB: u64 = 1024 * 1024;
+        // the configured size while it needs few enough parts
+        assert_eq!(part_size(0, 4 * MB), 4 * MB);
+        assert_eq!(part_size(10 * MB, 4 * MB), 4 * MB);
+        assert_eq!(part_size(MAX_PARTS * 4 * MB, 4 * MB), 4 * MB);
+        // grown and aligned beyond that
+        assert_eq!(part_size(MAX_PARTS * 4 * MB + 1, 4 * MB), 5 * MB);
+        assert_eq!(part_size(MAX_UPLOAD_SIZE, 4 * MB), MAX_PART_SIZE);
+        for size in [MAX_PARTS * MB + 1, 100 * 1024 * MB, MAX_UPLOAD_SIZE - 1, MAX_UPLOAD_SIZE] {
+            let part_size = part_size(size, MB);
+            assert!(size.div_ceil(part_size) <= MAX_PARTS, "{}", size);
+            assert_eq!(part_size % PART_SIZE_ALIGN, 0, "{}", size);
+        }
+        assert_eq!(part_size(10, 0), 1);
+    }
+
+    #[test]
+    fn resumes_interrupted_upload() {
+        let drive = MemoryDrive::new();
+        let dir = workdir("upload-resume");
+        let mut job = interrupted(&drive, &dir, b"aaaabbbbcc");
+        let file = job.file.clone().unwrap();
+        let oss_args = job.oss_args.clone().unwrap();
+        // the first part made it to the server before the restart
+        let upload_id = drive.get_pre_upload_info(&oss_args).unwrap();
+        let part = drive
+            .upload_chunk(&file, &oss_args, &upload_id, 1, Bytes::from_static(b"aaaa"))
+            .unwrap();
+        job.upload_id = Some(upload_id);
+        job.parts.push(part);
+        save(&dir, &job);
+        // uploaded parts aren't read again
+        fs::write(dir.join(format!("1.{}", DATA_EXTENSION)), b"xxxxbbbbcc").unwrap();
+        // data left by an upload finished just before the restart
+        fs::write(dir.join(format!("2.{}", DATA_EXTENSION)), b"done").unwrap();
+
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let queue = {
+            let finished = finished.clone();
+            UploadQueue::new(drive.clone(), dir.clone(), 1, move |job, file| {
+                finished.lock().push((job.id, file.id.clone()));
+            })
+        };
+        wait_for("upload", || !finished.lock().is_empty());
+        assert_eq!(*finished.lock(), vec![(1, file.id.clone())]);
+        assert_eq!(drive.content(&file.id).unwrap(), Bytes::from_static(b"aaaabbbbcc"));
+        assert!(queue.status().is_empty());
+        assert!(!dir.join(format!("1.{}", JOURNAL_EXTENSION)).exists());
+        assert!(!dir.join(format!("1.{}", DATA_EXTENSION)).exists());
+        assert!(!dir.join(format!("2.{}", DATA_EXTENSION)).exists());
+        assert!(queue.read(1, 0, 4).unwrap().is_none());
+        queue.stop();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_upload_is_retried() {
+        let drive = MemoryDrive::new();
+        let dir = workdir("upload-failed");
+        let mut job = interrupted(&drive, &dir, b"aaaabbbbcc");
+        // the object to upload into is gone from the server
+        job.oss_args.as_mut().unwrap().key = "gone".to_string();
+        save(&dir, &job);
+
+        let queue = UploadQueue::new(drive.clone(), dir.clone(), 1, |_, _| {});
+        wait_for("retry", || {
+            queue
+                .status()
+                .iter()
+                .any(|status| matches!(status.phase, UploadPhase::Retrying))
+        });
+        let status = queue.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].attempts, 1);
+        assert_eq!(status[0].uploaded_parts, 0);
+        assert_eq!(status[0].total_parts, 3);
+        assert!(status[0].last_error.as_deref().unwrap().contains("object gone not found"));
+        // the data stays readable while the upload is pending
+        assert_eq!(queue.read(1, 4, 4).unwrap().unwrap(), Bytes::from_static(b"bbbb"));
+        // the attempt is saved for the next mount
+        let journal = load_journals(&dir);
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal[0].attempts, 1);
+        assert_eq!(journal[0].failures, 1);
+        assert!(drive.content(&job.file.unwrap().id).is_none());
+        queue.stop();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn gives_up_uploads_that_keep_failing() {
+        let drive = MemoryDrive::new();
+        let dir = workdir("upload-give-up");
+        let mut job = interrupted(&drive, &dir, b"aaaabbbbcc");
+        job.oss_args.as_mut().unwrap().key = "gone".to_string();
+        job.attempts = MAX_FAILURES - 1;
+        job.failures = MAX_FAILURES - 1;
+        save(&dir, &job);
+
+        let failed = |queue: &UploadQueue<MemoryDrive>| {
+            queue
+                .status()
+                .iter()
+                .any(|status| matches!(status.phase, UploadPhase::Failed))
+        };
+        let queue = UploadQueue::new(drive.clone(), dir.clone(), 1, |_, _| {});
+        wait_for("give up", || failed(&queue));
+        assert_eq!(load_journals(&dir)[0].failures, MAX_FAILURES);
+        // the data is kept and the upload isn't resumed
+        assert!(dir.join(format!("1.{}", DATA_EXTENSION)).exists());
+        queue.stop();
+        let queue = UploadQueue::new(drive.clone(), dir.clone(), 1, |_, _| {});
+        assert!(failed(&queue));
+        queue.stop();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn restarts_upload_the_server_dropped() {
+        let drive = MemoryDrive::new();
+        let dir = workdir("upload-restart");
+        let mut job = interrupted(&drive, &dir, b"aaaabbbbcc");
+        let stale = job.file.clone().unwrap();
+        // OSS no longer knows the multipart upload, retrying its parts can't help
+        job.upload_id = Some("U-gone".to_string());
+        save(&dir, &job);
+
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let queue = {
+            let finished = finished.clone();
+            UploadQueue::new(drive.clone(), dir.clone(), 1, move |_, file| {
+                finished.lock().push(file.clone());
+            })
+        };
+        wait_for("upload", || !finished.lock().is_empty());
+        let file = finished.lock()[0].clone();
+        assert_ne!(file.id, stale.id);
+        assert_eq!(drive.content(&file.id).unwrap(), Bytes::from_static(b"aaaabbbbcc"));
+        assert_eq!(drive.find("", "a").unwrap().id, file.id);
+        queue.stop();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -2,36 +2,52 @@
 //!
 //! https://github.com/gz/btfs is used as a reference.
 use std::ffi::{OsStr, OsString};
-use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{collections::BTreeMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::Bytes;
 use fuser::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory,ReplyCreate, ReplyEmpty, ReplyEntry,
-    ReplyOpen,ReplyWrite, Request, FUSE_ROOT_ID,
+    ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow, FUSE_ROOT_ID,
 };
-use tracing::{debug,info,error};
+use parking_lot::Mutex;
+use tracing::{debug, error, warn};
 
-use sha1::{Sha1, Digest};
 use serde::de::DeserializeOwned;
 use quick_xml::de::from_str;
-use quick_xml::Writer;
-use quick_xml::se::Serializer as XmlSerializer;
 use serde_json::json;
-use serde::{Serialize,Deserialize};
+use serde::Deserialize;
 
 
 
-use crate::cache::Cache;
-use crate::drive::{PikpakDrive, PikpakFile};
+use crate::cache::{Cache, NegativeCache};
+use crate::drive::{DriveBackend, PikpakFile};
 use crate::drive::model::*;
 
 use crate::error::Error;
-use crate::file_cache::FileCache;
-
-const TTL: Duration = Duration::from_secs(1);
+use crate::disk_cache::DiskCache;
+use crate::file_cache::{FileCache, FileCacheConfig};
+use crate::inode_table::InodeTable;
+use crate::staging::StagingFile;
+use crate::thread_pool::ThreadPool;
+use crate::upload_queue::{UploadJob, UploadQueue, MAX_UPLOAD_SIZE};
+
+const NEGATIVE_CACHE_SIZE: u64 = 10000;
+const STAGING_DIR: &str = "staging";
+const BLOCKS_DIR: &str = "blocks";
+const UPLOAD_DIR: &str = "uploads";
 const BLOCK_SIZE: u64 = 4194304;
+const QUOTA_CACHE_TTL: Duration = Duration::from_secs(30);
+const STATFS_BLOCK_SIZE: u64 = 4096;
+/// PikPak doesn't limit the number of files, report this many as free
+const STATFS_FREE_FILES: u64 = 1_000_000;
+const MAX_NAME_LENGTH: u32 = 255;
+/// A copy made by PikPak may take a moment to show up in its folder
+const COPY_LIST_ATTEMPTS: u32 = 10;
+const COPY_LIST_INTERVAL: Duration = Duration::from_millis(500);
 
 
 
@@ -39,10 +55,56 @@ const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
 const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
 
 
+/// Attributes set through `setattr` that PikPak can't store, kept in memory
+/// on top of the attributes of the file
+#[derive(Debug, Clone, Default, PartialEq)]
+struct LocalAttr {
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+    mode: Option<u16>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl LocalAttr {
+    fn apply(&self, attr: &mut FileAttr) {
+        if let Some(atime) = self.atime {
+            attr.atime = atime;
+        }
+        if let Some(mtime) = self.mtime {
+            attr.mtime = mtime;
+        }
+        if let Some(mode) = self.mode {
+            attr.perm = mode;
+        }
+        if let Some(uid) = self.uid {
+            attr.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            attr.gid = gid;
+        }
+    }
+}
+
+/// Changes requested by a `setattr` call
+#[derive(Debug, Default)]
+struct AttrChanges {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Inode {
     children: BTreeMap<OsString, u64>,
     parent: u64,
+    attr: LocalAttr,
+    /// Children were evicted since the last listing, a name missing from
+    /// `children` may still exist
+    evicted: bool,
 }
 
 impl Inode {
@@ -50,6 +112,8 @@ impl Inode {
         Self {
             children: BTreeMap::new(),
             parent,
+            attr: LocalAttr::default(),
+            evicted: false,
         }
     }
 
@@ -59,78 +123,380 @@ impl Inode {
 }
 
 
-#[derive(Debug, Clone)]
+/// Data written through a file handle
+#[derive(Debug, Default)]
 struct UploadState {
-    size: u64,
-    buffer: BytesMut,
-    chunk_count: u64,
-    chunk: u64,
-    upload_id: String,
-    oss_args: Option<OssArgs>,
-    sha1: Option<String>,
-    upload_tags:CompleteMultipartUpload,
+    /// Inode the data is written to
+    ino: u64,
+    staging: Option<StagingFile>,
+    /// Largest size the file may grow to
+    size_limit: u64,
 }
 
-impl Default for UploadState {
-    fn default() -> Self {
-        let mut upload_tags = CompleteMultipartUpload{Part:vec![]};
-        Self {
-            size: 0,
-            buffer: BytesMut::new(),
-            chunk_count: 0,
-            chunk: 1,
-            upload_id: String::new(),
-            oss_args: None,
-            sha1: None,
-            upload_tags: upload_tags,
-        }
-    }
-}
 
+#[derive(Debug, Clone)]
+pub struct VfsConfig {
+    /// Working directory for persistent state such as inode numbers and the
+    /// upload queue, queued uploads are only resumed after a restart with it
+    pub workdir: Option<PathBuf>,
+    /// Directory for files being written and downloaded blocks, files being
+    /// written default to the working directory
+    pub cache_dir: Option<PathBuf>,
+    /// Maximum size of the downloaded blocks kept in `cache_dir`, 0 disables
+    /// the download cache
+    pub cache_max_size: u64,
+    pub read_buffer_size: usize,
+    /// Maximum size of the downloaded blocks kept in memory
+    pub memory_cache_size: u64,
+    /// Number of read buffers prefetched ahead of sequential reads
+    pub read_ahead: usize,
+    /// Number of parallel ranged requests a read buffer is downloaded with
+    pub download_connections: usize,
+    /// Number of times a failed download is retried, resuming after the
+    /// bytes already received
+    pub download_retries: u32,
+    /// Reply to reads with the data received so far instead of `EIO` when a
+    /// download keeps failing
+    pub short_reads: bool,
+    /// Maximum number of cached download links
+    pub url_cache_size: u64,
+    /// Time-to-live of cached download links in seconds
+    pub url_cache_ttl: u64,
+    pub upload_buffer_size: usize,
+    /// Number of parts of a file uploaded at the same time
+    pub upload_concurrency: usize,
+    /// Time-to-live of cached directory listings in seconds
+    pub dir_cache_ttl: u64,
+    /// Maximum number of cached directory listings
+    pub dir_cache_size: u64,
+    /// How long the kernel may cache file attributes
+    pub attr_timeout: Duration,
+    /// How long the kernel may cache names and the attributes returned with them
+    pub entry_timeout: Duration,
+    /// How long nonexistent names are cached, zero disables negative caching
+    pub negative_timeout: Duration,
+    /// Number of threads handling FUSE requests
+    pub worker_threads: usize,
+    /// Keep the mode and owner set by chmod/chown in memory, they're
+    /// ignored otherwise
+    pub store_permissions: bool,
+}
 
-pub struct PikpakDriveFileSystem {
-    drive: PikpakDrive,
-    file_cache: FileCache,
+/// Directory tree known to the filesystem
+#[derive(Debug)]
+struct Tree {
     files: BTreeMap<u64, PikpakFile>,
     inodes: BTreeMap<u64, Inode>,
-    next_inode: u64,
-    next_fh: u64,
+    inode_table: InodeTable,
+    /// Upload job id -> file whose upload was queued by this mount, jobs
+    /// resumed from an earlier mount refer to inodes that may have been
+    /// reused and aren't in here
+    queued: BTreeMap<u64, u64>,
+    /// Files removed while waiting for upload, they're deleted from the
+    /// server once uploaded
+    discarded: BTreeSet<u64>,
+    // inode -> id of the file on the server the pending upload replaces,
+    // the old file is hidden from listings until it's moved to the trash
+    replaced: BTreeMap<u64, String>,
+}
+
+impl Tree {
+    /// Replace the children of `ino` with a fresh listing
+    fn apply_listing(&mut self, ino: u64, files: &[PikpakFile]) -> Result<(), Error> {
+        let mut inode = self.inodes.get(&ino).ok_or(Error::NoEntry)?.clone();
+        // 同一个文件 id 始终复用同一个 inode
+        // files still being written or uploaded aren't listed by the server yet
+        let mut to_remove = inode
+            .children
+            .iter()
+            .filter(|(_, child)| self.files.get(child).map(|f| !f.id.is_empty()).unwrap_or(true))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for file in files {
+            if self.replaced.values().any(|id| *id == file.id) {
+                continue;
+            }
+            let name = OsString::from(file.name.clone());
+            to_remove.retain(|n| n != &name);
+            let child_ino = self.inode_table.get_or_insert(&file.id);
+            if let Some(old_ino) = inode.children.insert(name, child_ino) {
+                if old_ino != child_ino {
+                    self.remove_inode(old_ino);
+                }
+            }
+            self.files.insert(child_ino, file.clone());
+            self.inodes.entry(child_ino).or_insert_with(|| Inode::new(ino)).parent = ino;
+        }
+        for name in to_remove {
+            if let Some(ino_remove) = inode.children.remove(&name) {
+                debug!(inode = ino_remove, name = %Path::new(&name).display(), "remove outdated inode");
+                self.remove_inode(ino_remove);
+            }
+        }
+        inode.evicted = false;
+        self.inodes.insert(ino, inode);
+        self.inode_table.maybe_persist();
+        Ok(())
+    }
+
+    /// Drop a file the kernel forgot from memory, its inode number stays
+    /// bound to the file id and is found again by the next listing, see
+    /// `InodeTable::evict`. Folders with loaded children, files not uploaded
+    /// yet and files with local attributes are kept.
+    fn evict(&mut self, ino: u64) {
+        if ino == FUSE_ROOT_ID
+            || self.is_queued(ino)
+            || self.replaced.contains_key(&ino)
+            || self.files.get(&ino).map(|file| file.id.is_empty()).unwrap_or(true)
+        {
+            return;
+        }
+        let (parent, name) = match self.inodes.get(&ino) {
+            Some(inode) if inode.children.is_empty() && inode.attr == LocalAttr::default() => {
+                let name = OsString::from(self.files[&ino].name.clone());
+                (inode.parent, name)
+            }
+            _ => return,
+        };
+        if let Some(parent_inode) = self.inodes.get_mut(&parent) {
+            if parent_inode.children.get(&name) == Some(&ino) {
+                parent_inode.children.remove(&name);
+                parent_inode.evicted = true;
+            }
+        }
+        debug!(inode = ino, name = %Path::new(&name).display(), "evict forgotten inode");
+        self.files.remove(&ino);
+        self.inodes.remove(&ino);
+        self.inode_table.evict(ino);
+    }
+
+    /// Drop an inode that is no longer part of the tree, the inode number is
+    /// kept until the kernel forgets it
+    fn remove_inode(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            let children = std::mem::take(&mut inode.children);
+            for child in children.into_values() {
+                self.remove_inode(child);
+            }
+        }
+        if self.inode_table.lookup_count(ino) == 0 {
+            self.files.remove(&ino);
+            self.inodes.remove(&ino);
+            self.inode_table.remove(ino);
+        }
+    }
+
+    /// Insert a file created in the folder `parent`
+    fn add_child(&mut self, parent: u64, ino: u64, file: PikpakFile) {
+        let name = OsString::from(file.name.clone());
+        if let Some(parent_inode) = self.inodes.get_mut(&parent) {
+            if let Some(old_ino) = parent_inode.children.insert(name, ino) {
+                if old_ino != ino {
+                    self.remove_inode(old_ino);
+                }
+            }
+        }
+        self.files.insert(ino, file);
+        self.inodes.entry(ino).or_insert_with(|| Inode::new(parent)).parent = parent;
+    }
+
+    /// Remove the child `name` of `parent`
+    fn remove_child(&mut self, parent: u64, name: &OsStr) -> Option<u64> {
+        let ino = self.inodes.get_mut(&parent)?.children.remove(name)?;
+        if self.files.get(&ino).map(|file| file.id.is_empty()).unwrap_or(false) {
+            self.discarded.insert(ino);
+        }
+        self.remove_inode(ino);
+        Some(ino)
+    }
+
+    /// Whether `ancestor` is `ino` or one of the folders containing it
+    fn is_ancestor(&self, ancestor: u64, mut ino: u64) -> bool {
+        loop {
+            if ino == ancestor {
+                return true;
+            }
+            match self.inodes.get(&ino) {
+                Some(inode) if ino != FUSE_ROOT_ID => ino = inode.parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Move the child `name` of `parent` to `new_name` in `new_parent`,
+    /// `renamed` is the file returned by the server if it was renamed
+    fn move_child(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        renamed: Option<PikpakFile>,
+    ) {
+        let ino = match self.inodes.get_mut(&parent).and_then(|inode| inode.children.remove(name)) {
+            Some(ino) => ino,
+            None => return,
+        };
+        let new_parent_id = self.files.get(&new_parent).map(|file| file.id.clone());
+        let mut file = match (renamed, self.files.remove(&ino)) {
+            (Some(renamed), _) => renamed,
+            (None, Some(file)) => file,
+            (None, None) => return,
+        };
+        file.name = new_name.to_string_lossy().to_string();
+        if let Some(parent_id) = new_parent_id {
+            file.parent_id = parent_id;
+        }
+        self.add_child(new_parent, ino, file);
+    }
+
+    /// Attributes of `ino` with the locally set ones applied
+    fn file_attr(&self, ino: u64) -> Option<FileAttr> {
+        let mut attr = self.files.get(&ino)?.to_file_attr(ino);
+        if let Some(inode) = self.inodes.get(&ino) {
+            inode.attr.apply(&mut attr);
+        }
+        Some(attr)
+    }
+
+    /// Whether an upload of `ino` queued by this mount hasn't finished yet
+    fn is_queued(&self, ino: u64) -> bool {
+        self.queued.values().any(|queued| *queued == ino)
+    }
+
+    /// Whether `ino` is still reachable from its parent
+    fn is_attached(&self, ino: u64) -> bool {
+        if ino == FUSE_ROOT_ID {
+            return true;
+        }
+        self.inodes
+            .get(&ino)
+            .and_then(|inode| self.inodes.get(&inode.parent))
+            .map(|parent| parent.children.values().any(|child| *child == ino))
+            .unwrap_or(false)
+    }
+}
+
+/// State shared by the worker threads
+struct Inner<D: DriveBackend> {
+    drive: D,
+    dir_cache: Cache,
+    negative_cache: NegativeCache,
+    attr_timeout: Duration,
+    entry_timeout: Duration,
+    negative_timeout: Duration,
+    store_permissions: bool,
+    file_cache: FileCache<D>,
+    tree: Mutex<Tree>,
+    next_fh: AtomicU64,
     upload_buffer_size:usize,
-    upload_state: UploadState,
+    staging_dir: PathBuf,
+    upload_queue: UploadQueue<D>,
+    // file handle -> upload state of a file being written
+    upload_states: Mutex<HashMap<u64, Arc<Mutex<UploadState>>>>,
+    // last fetched `(used_size, total_size)` and when it was fetched
+    quota: Mutex<Option<(Instant, (u64, u64))>>,
 }
 
-impl PikpakDriveFileSystem {
-    pub fn new(drive: PikpakDrive, read_buffer_size: usize,upload_buffer_size:usize) -> Self {
-        let file_cache = FileCache::new(drive.clone(), read_buffer_size);
-        Self {
-            drive,
-            file_cache,
+impl<D: DriveBackend> Drop for Inner<D> {
+    fn drop(&mut self) {
+        // the callback can't reach the tree anymore
+        self.upload_queue.stop();
+    }
+}
+
+pub struct PikpakDriveFileSystem<D: DriveBackend> {
+    inner: Arc<Inner<D>>,
+    pool: ThreadPool,
+}
+
+impl<D: DriveBackend> PikpakDriveFileSystem<D> {
+    pub fn new(drive: D, config: VfsConfig) -> Self {
+        let disk_cache = match &config.cache_dir {
+            Some(cache_dir) if config.cache_max_size > 0 => match DiskCache::new(
+                cache_dir.join(BLOCKS_DIR),
+                config.cache_max_size,
+                config.read_buffer_size as u64,
+            ) {
+                Ok(disk_cache) => Some(disk_cache),
+                Err(err) => {
+                    error!(error = %err, "open disk cache failed, download cache disabled");
+                    None
+                }
+            },
+            _ => None,
+        };
+        let file_cache_config = FileCacheConfig {
+            read_buffer_size: config.read_buffer_size,
+            read_ahead: config.read_ahead,
+            url_cache_size: config.url_cache_size,
+            url_cache_ttl: config.url_cache_ttl,
+            memory_cache_size: config.memory_cache_size,
+            download_connections: config.download_connections,
+            download_retries: config.download_retries,
+            short_reads: config.short_reads,
+        };
+        let file_cache = FileCache::new(drive.clone(), file_cache_config, disk_cache);
+        // private to this mount, the upload queue isn't resumed from there
+        let default_dir = std::env::temp_dir().join(format!("pikpak-fuse-{}", std::process::id()));
+        let staging_dir = config
+            .cache_dir
+            .clone()
+            .or_else(|| config.workdir.clone())
+            .unwrap_or_else(|| default_dir.clone())
+            .join(STAGING_DIR);
+        let upload_dir = config.workdir.clone().unwrap_or(default_dir).join(UPLOAD_DIR);
+        let tree = Tree {
             files: BTreeMap::new(),
             inodes: BTreeMap::new(),
-            next_inode: 1,
-            next_fh: 1,
-            upload_buffer_size:upload_buffer_size,
-            upload_state: UploadState::default(),
+            inode_table: InodeTable::new(config.workdir),
+            queued: BTreeMap::new(),
+            discarded: BTreeSet::new(),
+            replaced: BTreeMap::new(),
+        };
+        let inner = Arc::new_cyclic(|inner: &Weak<Inner<D>>| {
+            let inner = inner.clone();
+            let upload_queue = UploadQueue::new(drive.clone(), upload_dir, config.upload_concurrency, move |job, file| {
+                if let Some(inner) = inner.upgrade() {
+                    inner.upload_finished(job, file);
+                }
+            });
+            Inner {
+                drive,
+                dir_cache: Cache::new(config.dir_cache_size, config.dir_cache_ttl),
+                negative_cache: NegativeCache::new(NEGATIVE_CACHE_SIZE, config.negative_timeout),
+                attr_timeout: config.attr_timeout,
+                entry_timeout: config.entry_timeout,
+                negative_timeout: config.negative_timeout,
+                store_permissions: config.store_permissions,
+                file_cache,
+                tree: Mutex::new(tree),
+                next_fh: AtomicU64::new(1),
+                upload_buffer_size: config.upload_buffer_size,
+                staging_dir,
+                upload_queue,
+                upload_states: Mutex::new(HashMap::new()),
+                quota: Mutex::new(None),
+            }
+        });
+        Self {
+            inner,
+            pool: ThreadPool::new(config.worker_threads),
         }
     }
+}
 
-    /// Next inode number
-    fn next_inode(&mut self) -> u64 {
-        self.next_inode = self.next_inode.wrapping_add(1);
-        self.next_inode
-    }
-
+impl<D: DriveBackend> Inner<D> {
     /// Next file handler
-    fn next_fh(&mut self) -> u64 {
-        self.next_fh = self.next_fh.wrapping_add(1);
-        self.next_fh
+    fn next_fh(&self) -> u64 {
+        self.next_fh.fetch_add(1, Ordering::SeqCst) + 1
     }
 
 
     fn allocate_next_file_handle(&self, read: bool, write: bool) -> u64 {
-        let mut fh = self.next_fh.wrapping_add(1);
+        let mut fh = self.next_fh();
         // Assert that we haven't run out of file handles
-        assert!(fh < FILE_HANDLE_WRITE_BIT && fh < FILE_HANDLE_READ_BIT);
+        assert!(fh < FILE_HANDLE_WRITE_BIT);
         if read {
             fh |= FILE_HANDLE_READ_BIT;
         }
@@ -141,107 +507,94 @@ impl PikpakDriveFileSystem {
         fh
     }
 
-    fn init(&mut self) -> Result<(), Error> {
+    /// Upload state of the file handle `fh`
+    fn upload_state(&self, fh: u64) -> Arc<Mutex<UploadState>> {
+        self.upload_states.lock().entry(fh).or_default().clone()
+    }
+
+    fn init(&self) -> Result<(), Error> {
         let mut root_file = PikpakFile::new_root();
         // let (used_size, _) = self.drive.get_quota().map_err(|_| Error::ApiCallFailed)?;
         // root_file.size = used_size.to_string();
         let root_inode = Inode::new(0);
-        self.inodes.insert(FUSE_ROOT_ID, root_inode);
-        self.files.insert(FUSE_ROOT_ID, root_file);
+        let mut tree = self.tree.lock();
+        tree.inodes.insert(FUSE_ROOT_ID, root_inode);
+        tree.files.insert(FUSE_ROOT_ID, root_file);
         Ok(())
     }
 
-    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, Error> {
+    fn lookup(&self, parent: u64, name: &OsStr) -> Result<FileAttr, Error> {
         let file_name = name.to_string_lossy().to_string();
         debug!(file_name = file_name, "lookup for macos special file");
 
-        if file_name == ".DS_Store" || file_name.starts_with("._") || file_name.starts_with(".") {
+        if file_name == ".DS_Store" || file_name.starts_with("._") {
             error!(file_name = file_name, "lookup for macos special file");
             return Err(Error::ChildNotFound);
         }
 
-        let mut parent_inode = self
-            .inodes
-            .get(&parent)
-            .ok_or(Error::ParentNotFound)?
-            .clone();
-        if parent_inode.children.is_empty() {
+        let loaded = {
+            let tree = self.tree.lock();
+            let parent_inode = tree.inodes.get(&parent).ok_or(Error::ParentNotFound)?;
+            if let Some(inode) = parent_inode.children.get(name) {
+                return tree.file_attr(*inode).ok_or(Error::NoEntry);
+            }
+            !parent_inode.children.is_empty() && !parent_inode.evicted
+        };
+        if self.negative_cache.contains(parent, name) {
+            debug!(parent = parent, file_name = file_name, "lookup hit negative cache");
+            return Err(Error::ChildNotFound);
+        }
+        if !loaded {
             // Parent inode isn't loaded yet
             debug!(parent = parent, "readdir missing parent in lookup");
             self.readdir(parent, 0)?;
-            parent_inode = self
-                .inodes
-                .get(&parent)
-                .ok_or(Error::ParentNotFound)?
-                .clone();
         }
-        let inode = parent_inode
-            .children
-            .get(name)
-            .ok_or(Error::ChildNotFound)?;
-        let file = self.files.get(inode).ok_or(Error::NoEntry)?;
-        Ok(file.to_file_attr(*inode))
+        let tree = self.tree.lock();
+        let parent_inode = tree.inodes.get(&parent).ok_or(Error::ParentNotFound)?;
+        let inode = match parent_inode.children.get(name) {
+            Some(inode) => inode,
+            None => {
+                self.negative_cache.insert(parent, name);
+                return Err(Error::ChildNotFound);
+            }
+        };
+        tree.file_attr(*inode).ok_or(Error::NoEntry)
     }
 
-    fn readdir(&mut self, ino: u64, offset: i64) -> Result<Vec<(u64, FileType, String)>, Error> {
+    fn readdir(&self, ino: u64, offset: i64) -> Result<Vec<(u64, FileType, String)>, Error> {
         debug!(ino = ino, "readdir");
         let mut entries = Vec::new();
-        let mut inode = self.inodes.get(&ino).ok_or(Error::NoEntry)?.clone();
         if offset == 0 {
+            let (parent, parent_file_id, dir_name) = {
+                let tree = self.tree.lock();
+                let inode = tree.inodes.get(&ino).ok_or(Error::NoEntry)?;
+                let file = tree.files.get(&ino).ok_or(Error::NoEntry)?;
+                (inode.parent, file.id.clone(), file.name.clone())
+            };
             entries.push((ino, FileType::Directory, ".".to_string()));
-            entries.push((inode.parent, FileType::Directory, String::from("..")));
-            let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-            let parent_file_id = &file.id;
-            let files = self
-                .drive
-                .list_all(parent_file_id)
-                .map_err(|_| Error::ApiCallFailed)?;
+            entries.push((parent, FileType::Directory, String::from("..")));
+            // list without holding the tree lock
+            let (files, generation) = self.list_dir(&parent_file_id)?;
             debug!(
                 inode = ino,
                 "total {} files in directory {}",
                 files.len(),
-                file.name
+                dir_name
             );
-
-            // 删除所有旧的child 重新添加
-            let mut to_remove = inode.children.keys().cloned().collect::<Vec<_>>();
-            for file in &files {
-                let name = OsString::from(file.name.clone());
-                to_remove.retain(|n| n != &name);
-                let new_inode = self.next_inode();
-                inode.add_child(name, new_inode);
-                self.files.insert(new_inode, file.clone());
-                self.inodes.entry(new_inode).or_insert_with(|| Inode::new(ino));
-
-                //  如果存在名称则删除？
-                // if inode.children.contains_key(&name) {
-                //     // file already exists
-                //     to_remove.retain(|n| n != &name);
-                // } else {
-                //     let new_inode = self.next_inode();
-                //     inode.add_child(name, new_inode);
-                //     self.files.insert(new_inode, file.clone());
-                //     self.inodes
-                //         .entry(new_inode)
-                //         .or_insert_with(|| Inode::new(ino));
-                // }
-
-
-            }
-            if !to_remove.is_empty() {
-                for name in to_remove {
-                    if let Some(ino_remove) = inode.children.remove(&name) {
-                        debug!(inode = ino_remove, name = %Path::new(&name).display(), "remove outdated inode");
-                        self.files.remove(&ino_remove);
-                        self.inodes.remove(&ino_remove);
-                    }
-                }
+            let mut tree = self.tree.lock();
+            // a change made while listing is already in the tree and
+            // missing from the listing
+            if self.dir_cache.generation(&parent_file_id) == generation {
+                tree.apply_listing(ino, &files)?;
+            } else {
+                debug!(inode = ino, "directory changed while listing, listing dropped");
             }
-            self.inodes.insert(ino, inode.clone());
         }
 
+        let tree = self.tree.lock();
+        let inode = tree.inodes.get(&ino).ok_or(Error::NoEntry)?;
         for child_ino in inode.children.values().skip(offset as usize) {
-            let file = self.files.get(child_ino).ok_or(Error::ChildNotFound)?;
+            let file = tree.files.get(child_ino).ok_or(Error::ChildNotFound)?;
             let kind = if file.kind.eq("drive#folder"){
                 FileType::Directory
             }else{
@@ -253,21 +606,106 @@ impl PikpakDriveFileSystem {
         Ok(entries)
     }
 
-    fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
-        let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-        debug!(inode = ino, name = %file.name, fh = fh, offset = offset, size = size, "read");
-        if offset >= file.size.parse::<i64>().unwrap() {
+    /// List a folder, served from the directory cache when possible, along
+    /// with the generation of the folder the listing belongs to
+    fn list_dir(&self, folder_id: &str) -> Result<(Vec<PikpakFile>, u64), Error> {
+        let generation = self.dir_cache.generation(folder_id);
+        if let Some(files) = self.dir_cache.get(folder_id) {
+            return Ok((files, generation));
+        }
+        let files = self
+            .drive
+            .list_all(folder_id)
+            .map_err(|_| Error::ApiCallFailed)?;
+        self.dir_cache.insert(folder_id.to_string(), files.clone(), generation);
+        Ok((files, generation))
+    }
+
+    /// Invalidate the cached listing of the folder `ino`
+    fn invalidate_dir(&self, ino: u64) {
+        if let Some(file) = self.tree.lock().files.get(&ino) {
+            self.dir_cache.invalidate(&file.id);
+        }
+    }
+
+    /// File id of `ino`
+    fn file_id(&self, ino: u64) -> Result<String, Error> {
+        let tree = self.tree.lock();
+        let file = tree.files.get(&ino).ok_or(Error::NoEntry)?;
+        Ok(file.id.clone())
+    }
+
+    fn read(&self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+        let file_size = {
+            let tree = self.tree.lock();
+            let file = tree.files.get(&ino).ok_or(Error::NoEntry)?;
+            debug!(inode = ino, name = %file.name, fh = fh, offset = offset, size = size, "read");
+            file.size.parse::<u64>().unwrap()
+        };
+        if offset >= file_size as i64 {
             return Ok(Bytes::new());
         }
-        let size = std::cmp::min(size, file.size.parse::<u64>().unwrap().saturating_sub(offset as u64) as u32);
+        let size = std::cmp::min(size, file_size.saturating_sub(offset as u64) as u32);
+        if let Some(data) = self.read_pending(ino, offset as u64, size as usize)? {
+            return Ok(data);
+        }
+        // the handle was opened before the file was uploaded or replaced,
+        // e.g. by a truncate through another handle
+        let file = self.tree.lock().files.get(&ino).cloned().ok_or(Error::NoEntry)?;
+        if self.file_cache.file_id(fh).map(|id| id != file.id).unwrap_or(false) {
+            let file_size = file.size.parse::<u64>().unwrap_or_default();
+            self.file_cache.open(fh, file.id.clone(), file_size, file.content_version());
+        }
         self.file_cache.read(fh, offset, size)
     }
 
+    /// Read a file that isn't on the server yet from the data written to
+    /// it, `None` if it's uploaded
+    fn read_pending(&self, ino: u64, offset: u64, size: usize) -> Result<Option<Bytes>, Error> {
+        let job_id = {
+            let tree = self.tree.lock();
+            match tree.files.get(&ino) {
+                Some(file) if file.id.is_empty() => {}
+                _ => return Ok(None),
+            }
+            tree.queued
+                .iter()
+                .rev()
+                .find(|(_, queued)| **queued == ino)
+                .map(|(job_id, _)| *job_id)
+        };
+        // still being written through a file handle
+        let upload_states: Vec<_> = self.upload_states.lock().values().cloned().collect();
+        for upload_state in upload_states {
+            let upload_state = upload_state.lock();
+            if let (true, Some(staging)) = (upload_state.ino == ino, upload_state.staging.as_ref()) {
+                return staging.read_at(offset, size).map(Some).map_err(|err| {
+                    error!(inode = ino, error = %err, "read staging file failed");
+                    Error::Io
+                });
+            }
+        }
+        // waiting in the upload queue
+        if let Some(job_id) = job_id {
+            match self.upload_queue.read(job_id, offset, size) {
+                Ok(Some(data)) => return Ok(Some(data)),
+                Ok(None) => {}
+                Err(err) => {
+                    error!(inode = ino, error = %err, "read queued upload failed");
+                    return Err(Error::Io);
+                }
+            }
+        }
+        match self.tree.lock().files.get(&ino) {
+            // uploaded while reading
+            Some(file) if !file.id.is_empty() => Ok(None),
+            _ => Err(Error::NoEntry),
+        }
+    }
 
-
-    fn prepare_for_upload(&mut self,ino: u64, fh: u64) -> Result<bool, Error> {
-        debug!(chunk_count=self.upload_state.chunk_count, " prepare_for_upload upload_state.chunk_count");
-        let mut file = match self.files.get(&ino) {
+    /// Whether writes to `ino` should be uploaded
+    fn prepare_for_upload(&self, ino: u64) -> Result<bool, Error> {
+        let file = match self.tree.lock().files.get(&ino) {
             Some(file) => file.clone(),
             None => {
                 error!(inode = ino, "file not found");
@@ -283,385 +721,617 @@ impl PikpakDriveFileSystem {
         }
 
         // 忽略 macOS 上的一些特殊文件
-        if file.name == ".DS_Store" || file.name.starts_with(".") {
+        if file.name == ".DS_Store" || file.name.starts_with("._") {
             return Ok(false);
         }
+        Ok(true)
+    }
 
+    /// Staging file for the data written through the file handle `fh`
+    fn create_staging(&self, ino: u64, fh: u64) -> Result<StagingFile, Error> {
+        // file handles restart at 1 in every mount sharing the directory
+        let name = format!("{}-{:x}.upload", std::process::id(), fh);
+        StagingFile::create(&self.staging_dir, &name).map_err(|err| {
+            error!(inode = ino, error = %err, "create staging file failed");
+            Error::Io
+        })
+    }
 
-        if self.upload_state.chunk_count == 0 {
-            let size = self.upload_state.size;
-            debug!(file_id=file.id, name=%file.name, size=size, "prepare_for_upload");
-            if !file.id.is_empty() {
-                return Ok(false);
-            }
-            // TODO: create parent folders?
-            debug!("prepare_for_upload after upload_state.chunk_count==0");
-            let upload_buffer_size = self.upload_buffer_size as u64;
-            let chunk_count =
-                size / upload_buffer_size + if size % upload_buffer_size != 0 { 1 } else { 0 };
-
-            debug!(chunk_count=chunk_count, "prepare_for_upload chunk_count");
-
-
-            self.upload_state.chunk_count = chunk_count;
-            debug!("uploading {} ({} bytes)...", file.name, size);
-            if size>0 {
-                let hash = file.clone().hash.unwrap();
-                let res = self
-                    .drive
-                    .create_file_with_proof(&file.name, &file.parent_id, &hash, size);
-
-                
-                let upload_response = match res {
-                    Ok(upload_response_info) => upload_response_info,
-                    Err(err) => {
-                        error!(file_name = file.name, error = %err, "create file with proof failed");
-                        return Ok(false);
+    /// Hand the data written through a file handle over to the upload queue,
+    /// an empty staging file left by a truncation is uploaded as an empty file
+    fn enqueue_upload(&self, upload_state: &mut UploadState, ino: u64) -> Result<(), Error> {
+        let staging = match upload_state.staging.take() {
+            Some(staging) => staging,
+            None => return Ok(()),
+        };
+        let file = {
+            let mut tree = self.tree.lock();
+            match tree.files.get(&ino) {
+                Some(file) if tree.is_attached(ino) => file.clone(),
+                _ => {
+                    debug!(inode = ino, "file removed before upload, written data dropped");
+                    tree.discarded.remove(&ino);
+                    let replaced = tree.replaced.remove(&ino);
+                    drop(tree);
+                    if let Some(old_id) = replaced {
+                        if let Err(err) = self.drive.trash_file(&old_id) {
+                            error!(inode = ino, error = %err, "trash replaced file failed");
+                        }
                     }
-                };
-
-              
-                debug!(file_name = upload_response.file.name, "upload response name");
-                let oss_args = OssArgs {
-                    bucket: upload_response.resumable.params.bucket.to_string(),
-                    key: upload_response.resumable.params.key.to_string(),
-                    endpoint: upload_response.resumable.params.endpoint.to_string(),
-                    access_key_id: upload_response.resumable.params.access_key_id.to_string(),
-                    access_key_secret: upload_response.resumable.params.access_key_secret.to_string(),
-                    security_token: upload_response.resumable.params.security_token.to_string(),
-                };
-                self.upload_state.oss_args = Some(oss_args);
-    
-                let oss_args = self.upload_state.oss_args.as_ref().unwrap();
-                let pre_upload_info = self.drive.get_pre_upload_info(&oss_args);
-                if let Err(err) = pre_upload_info {
-                    error!(file_name = file.name, error = %err, "get pre upload info failed");
-                    return Ok(false);
+                    return Ok(());
                 }
-               
-                self.upload_state.upload_id = match pre_upload_info {
-                    Ok(upload_id) => upload_id,
-                    Err(err) => {
-                        error!(file_name = file.name, error = %err, "get pre upload info failed");
-                        return Ok(false);
-                    }
-                };
-                debug!(file_name = file.name, upload_id = %self.upload_state.upload_id, "pre upload info get upload_id success");
             }
-        }
-        Ok(true)
+        };
+        // the tree stays locked until the job is recorded, the upload may
+        // finish before `enqueue` returns
+        let mut tree = self.tree.lock();
+        let replaces = tree.replaced.get(&ino).cloned();
+        let job_id = self
+            .upload_queue
+            .enqueue(staging, ino, &file.name, &file.parent_id, replaces, self.upload_buffer_size as u64)
+            .map_err(|err| {
+                error!(file_name = %file.name, error = %err, "queue upload failed");
+                Error::UploadFailed
+            })?;
+        tree.queued.insert(job_id, ino);
+        Ok(())
     }
 
-
-    fn maybe_upload_chunk(&mut self,remaining: bool,ino: u64, fh: u64)-> Result<(), Error>{
-        let chunk_size = if remaining {
-            // last chunk size maybe less than upload_buffer_size
-            self.upload_state.buffer.remaining()
-        } else {
-            self.upload_buffer_size
-        };
-        //let chunk_size = self.upload_state.buffer.remaining();
-        let current_chunk = self.upload_state.chunk;
-        debug!(chunk_size=chunk_size,"chunk_size is");
-        debug!(upload_state_buffer_remaining=self.upload_state.buffer.remaining(),"buffer remaining is");
-        debug!(current_chunk=current_chunk,"current_chunk is");
-        debug!(chunk_count=self.upload_state.chunk_count, "chunk_count is");
-
-        if chunk_size > 0
-        && self.upload_state.buffer.remaining() >= chunk_size
-        && current_chunk <= self.upload_state.chunk_count
-        {
-            debug!("maybe_upload_chunk after chunk_size>0");
-            let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-            let chunk_data = self.upload_state.buffer.split_to(chunk_size);
-
-            let upload_data = chunk_data.freeze();
-            let oss_args = match self.upload_state.oss_args.as_ref() {
-                Some(oss_args) => oss_args,
-                None => {
-                    error!(file_name = %file.name, "获取文件上传信息错误");
-                    return Err(Error::UploadFailed);
-                }
-            };
-            let res = self.drive.upload_chunk(file,oss_args,&self.upload_state.upload_id,current_chunk,upload_data.clone());
-            
-            let part = match res {
-                Ok(part) => part,
-                Err(err) => {
-                    error!(file_name = %file.name, error = %err, "上传分片失败，无法获取ETag");
-                    return Err(Error::UploadFailed);
-                }
-            };
-                
-            debug!(chunk_count = %self.upload_state.chunk_count, current_chunk=current_chunk, "upload chunk info");
-            self.upload_state.upload_tags.Part.push(part);
-
-             
-            if current_chunk == self.upload_state.chunk_count{
-                debug!(file_name = %file.name, "upload finished");
-                let mut buffer = Vec::new();
-                let mut ser = XmlSerializer::with_root(Writer::new_with_indent(&mut buffer, b' ', 4), Some("CompleteMultipartUpload"));
-                self.upload_state.upload_tags.serialize(&mut ser).unwrap();
-                let upload_tags = String::from_utf8(buffer).unwrap();
-                self.drive.complete_upload(file,upload_tags,oss_args,&self.upload_state.upload_id);
-                self.upload_state = UploadState::default();
-                return Ok(());
+    /// `(used_size, total_size)` of the drive, cached for a little while,
+    /// the last known quota is used if fetching it fails
+    fn quota(&self) -> anyhow::Result<(u64, u64)> {
+        let mut cached = self.quota.lock();
+        if let Some((fetched, quota)) = *cached {
+            if fetched.elapsed() < QUOTA_CACHE_TTL {
+                return Ok(quota);
             }
-            self.upload_state.chunk += 1;
         }
-        Ok(())
+        match self.drive.get_quota() {
+            Ok(quota) => {
+                *cached = Some((Instant::now(), quota));
+                Ok(quota)
+            }
+            Err(err) => match *cached {
+                Some((_, quota)) => {
+                    warn!(error = %err, "get quota failed, using the last known quota");
+                    Ok(quota)
+                }
+                None => Err(err),
+            },
+        }
     }
 
+    fn statfs(&self, reply: ReplyStatfs) {
+        let (used_size, total_size) = match self.quota() {
+            Ok(quota) => quota,
+            Err(err) => {
+                error!(error = %err, "get quota failed");
+                reply.error(libc::EIO);
+                return;
+            }
+        };
+        let blocks = total_size / STATFS_BLOCK_SIZE;
+        let free_blocks = total_size.saturating_sub(used_size) / STATFS_BLOCK_SIZE;
+        let files = self.tree.lock().files.len() as u64;
+        reply.statfs(
+            blocks,
+            free_blocks,
+            free_blocks,
+            files + STATFS_FREE_FILES,
+            STATFS_FREE_FILES,
+            STATFS_BLOCK_SIZE as u32,
+            MAX_NAME_LENGTH,
+            STATFS_BLOCK_SIZE as u32,
+        );
+    }
 
-
-
-    
-}
-
-impl Filesystem for PikpakDriveFileSystem {
-    fn init(
-        &mut self,
-        _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
-        if let Err(e) = self.init() {
-            return Err(e.into());
+    /// Largest file that can be uploaded, bounded by the free space of the
+    /// account and the OSS multipart limits
+    fn upload_size_limit(&self) -> u64 {
+        match self.quota() {
+            Ok((used, total)) if total > 0 => MAX_UPLOAD_SIZE.min(total.saturating_sub(used)),
+            Ok(_) => MAX_UPLOAD_SIZE,
+            Err(err) => {
+                debug!(error = %err, "get quota failed, upload size unchecked");
+                MAX_UPLOAD_SIZE
+            }
         }
-        Ok(())
     }
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let dirname = Path::new(name);
-        // 忽略 macOS 上的一些特殊文件
-        debug!(parent = parent, name = %dirname.display(), "lookup");
-        match self.lookup(parent, name) {
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
-            Err(e) => reply.error(e.into()),
+    fn setattr(&self, ino: u64, fh: Option<u64>, changes: AttrChanges) -> Result<FileAttr, Error> {
+        if let Some(size) = changes.size {
+            self.truncate(ino, fh, size)?;
+        }
+        let mut tree = self.tree.lock();
+        let inode = tree.inodes.get_mut(&ino).ok_or(Error::NoEntry)?;
+        // PikPak doesn't allow changing the times of a file, they're kept locally
+        if changes.atime.is_some() {
+            inode.attr.atime = changes.atime;
+        }
+        if changes.mtime.is_some() {
+            inode.attr.mtime = changes.mtime;
+        }
+        if self.store_permissions {
+            if let Some(mode) = changes.mode {
+                inode.attr.mode = Some((mode & 0o7777) as u16);
+            }
+            if changes.uid.is_some() {
+                inode.attr.uid = changes.uid;
+            }
+            if changes.gid.is_some() {
+                inode.attr.gid = changes.gid;
+            }
         }
+        tree.file_attr(ino).ok_or(Error::NoEntry)
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        if let Some(file) = self.files.get(&ino) {
-            debug!(inode = ino, name = %file.name, "getattr");
-            reply.attr(&TTL, &file.to_file_attr(ino))
+    /// Change the size of `ino`, a file already on the server is replaced by
+    /// a re-upload of its content truncated or zero-extended to `size`
+    fn truncate(&self, ino: u64, fh: Option<u64>, size: u64) -> Result<(), Error> {
+        let file = self.tree.lock().files.get(&ino).cloned().ok_or(Error::NoEntry)?;
+        if file.kind == "drive#folder" {
+            return Err(Error::IsDirectory);
+        }
+        let old_size = file.size.parse::<u64>().unwrap_or_default();
+        if size == old_size && (!file.id.is_empty() || size == 0) {
+            return Ok(());
+        }
+        if size > old_size && size > self.upload_size_limit() {
+            return Err(Error::FileTooLarge);
+        }
+        debug!(inode = ino, name = %file.name, old_size = old_size, size = size, "truncate");
+        if file.id.is_empty() {
+            self.truncate_staged(ino, fh, size)?;
         } else {
-            debug!(inode = ino, "getattr");
-            reply.error(libc::ENOENT);
+            self.truncate_uploaded(ino, fh, &file, size)?;
+        }
+        let mut tree = self.tree.lock();
+        if let Some(file) = tree.files.get_mut(&ino) {
+            file.size = size.to_string();
+            file.modified_time = DateTime::new(SystemTime::now());
+        }
+        if let Some(inode) = tree.inodes.get_mut(&ino) {
+            inode.attr.mtime = None;
         }
+        Ok(())
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        debug!(inode = ino, offset = offset, "readdir");
-        match self.readdir(ino, offset) {
-            Ok(entries) => {
-                // Offset of 0 means no offset.
-                // Non-zero offset means the passed offset has already been seen,
-                // and we should start after it.
-                let offset_add = if offset == 0 { 0 } else { offset + 1 };
-                for (i, (ino, kind, name)) in entries.into_iter().enumerate() {
-                    let buffer_full = reply.add(ino, offset_add + i as i64, kind, name);
-                    if buffer_full {
-                        break;
-                    }
-                }
-                reply.ok();
+    /// Truncate a file that isn't uploaded yet
+    fn truncate_staged(&self, ino: u64, fh: Option<u64>, size: u64) -> Result<(), Error> {
+        let upload_states: Vec<_> = self
+            .upload_states
+            .lock()
+            .iter()
+            .filter(|(handle, _)| fh.map(|fh| **handle == fh).unwrap_or(true))
+            .map(|(_, upload_state)| upload_state.clone())
+            .collect();
+        for upload_state in upload_states {
+            let mut upload_state = upload_state.lock();
+            if upload_state.ino != ino {
+                continue;
+            }
+            if let Some(staging) = upload_state.staging.as_mut() {
+                return staging.set_len(size).map_err(|err| {
+                    error!(inode = ino, error = %err, "truncate staging file failed");
+                    Error::Io
+                });
             }
-            Err(e) => reply.error(e.into()),
         }
+        let (file_size, queued) = {
+            let tree = self.tree.lock();
+            let file = tree.files.get(&ino).ok_or(Error::NoEntry)?;
+            (file.size.parse::<u64>().unwrap_or_default(), tree.is_queued(ino))
+        };
+        if file_size > 0 || queued {
+            // the written data is owned by the upload queue until it's uploaded
+            return Err(Error::Busy);
+        }
+        // nothing written yet, start the file with `size` zeros
+        let handle = fh.unwrap_or_else(|| self.next_fh());
+        let mut staging = self.create_staging(ino, handle)?;
+        staging.set_len(size).map_err(|err| {
+            error!(inode = ino, error = %err, "truncate staging file failed");
+            Error::Io
+        })?;
+        self.stage(ino, fh, staging)
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        debug!(inode = ino, "open");
-        if let Some((file_id, file_name, file_size)) = self
-            .files
-            .get(&ino)
-            .map(|f| (f.id.clone(), f.name.clone(), f.size.parse::<u64>().unwrap()))
+    /// Replace a file on the server by its content truncated to `size`
+    fn truncate_uploaded(&self, ino: u64, fh: Option<u64>, file: &PikpakFile, size: u64) -> Result<(), Error> {
+        let handle = fh.unwrap_or_else(|| self.next_fh());
+        let mut staging = self.create_staging(ino, handle)?;
+        let old_size = file.size.parse::<u64>().unwrap_or_default();
+        let keep = std::cmp::min(old_size, size);
+        if keep > 0 {
+            let read_fh = self.next_fh();
+            self.file_cache.open(read_fh, file.id.clone(), old_size, file.content_version());
+            let res = self.copy_to_staging(read_fh, keep, &mut staging);
+            self.file_cache.release(read_fh);
+            res?;
+        }
+        staging.set_len(size).map_err(|err| {
+            error!(inode = ino, error = %err, "truncate staging file failed");
+            Error::Io
+        })?;
+
+        // the file becomes a new file waiting for upload, the old one is
+        // kept until the upload finished
         {
-            debug!(inode = ino, name = %file_name, "open file");
-            // 忽略 macOS 上的一些特殊文件
-            if file_name == ".DS_Store" || file_name.starts_with("._") {
-                //reply.error(libc::ENOENT);
-                return;
+            let mut tree = self.tree.lock();
+            if let Some(file) = tree.files.get_mut(&ino) {
+                file.id = String::new();
+                file.phase = String::new();
+                file.hash = None;
             }
-
-            let fh = self.next_fh();
-            self.file_cache.open(fh, file_id, file_size);
-            reply.opened(fh, 0);
-        } else {
-            debug!(inode = ino, "open file");
-            reply.error(libc::ENOENT);
+            tree.replaced.insert(ino, file.id.clone());
         }
+        self.stage(ino, fh, staging)
     }
 
+    /// Copy the first `size` bytes of the file opened as `fh` to `staging`
+    fn copy_to_staging(&self, fh: u64, size: u64, staging: &mut StagingFile) -> Result<(), Error> {
+        let mut offset = 0;
+        while offset < size {
+            let len = std::cmp::min(BLOCK_SIZE, size - offset) as u32;
+            let data = self.file_cache.read(fh, offset as i64, len)?;
+            if data.is_empty() {
+                return Err(Error::ApiCallFailed);
+            }
+            staging.write_at(offset, &data).map_err(|err| {
+                error!(error = %err, "write staging file failed");
+                Error::Io
+            })?;
+            offset += data.len() as u64;
+        }
+        Ok(())
+    }
 
-    fn release(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        debug!(inode = ino, fh = fh, "release file");
-        self.file_cache.release(fh);
-        reply.ok();
+    /// Keep `staging` as the data of the file handle `fh`, or upload it right
+    /// away when the file isn't open
+    fn stage(&self, ino: u64, fh: Option<u64>, staging: StagingFile) -> Result<(), Error> {
+        match fh {
+            Some(fh) => {
+                let upload_state = self.upload_state(fh);
+                let mut upload_state = upload_state.lock();
+                upload_state.ino = ino;
+                upload_state.staging = Some(staging);
+                upload_state.size_limit = self.upload_size_limit();
+                Ok(())
+            }
+            None => {
+                let mut upload_state = UploadState {
+                    ino,
+                    staging: Some(staging),
+                    size_limit: 0,
+                };
+                self.enqueue_upload(&mut upload_state, ino)
+            }
+        }
     }
 
-    fn read(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        debug!(inode = ino, fh = fh, offset = offset, size = size, "read work here");
-        match self.read(ino, fh, offset, size) {
-            Ok(data) => reply.data(&data),
-            Err(e) => reply.error(e.into()),
+    /// Called by the upload queue once a file is on the server
+    fn upload_finished(&self, job: &UploadJob, uploaded: &PikpakFile) {
+        self.dir_cache.invalidate(&job.parent_id);
+        if let Some(old_id) = &job.replaces {
+            debug!(file_name = %job.name, old_id = %old_id, "trash replaced file");
+            if let Err(err) = self.drive.trash_file(old_id) {
+                error!(file_name = %job.name, error = %err, "trash replaced file failed");
+            }
+            let mut tree = self.tree.lock();
+            if tree.replaced.get(&job.ino) == Some(old_id) {
+                tree.replaced.remove(&job.ino);
+            }
+        }
+        let mut uploaded = uploaded.clone();
+        let mut name = job.name.clone();
+        let mut parent_id = job.parent_id.clone();
+        let ino = match self.tree.lock().queued.remove(&job.id) {
+            Some(ino) => ino,
+            None => return,
+        };
+        loop {
+            let mut tree = self.tree.lock();
+            if tree.discarded.remove(&ino) {
+                drop(tree);
+                debug!(file_name = %name, "file removed while uploading");
+                if let Err(err) = self.drive.remove_file(&uploaded.id) {
+                    error!(file_name = %name, error = %err, "remove discarded upload failed");
+                }
+                return;
+            }
+            let (new_name, new_parent_id) = match tree.files.get(&ino) {
+                Some(file) if file.id.is_empty() => (file.name.clone(), file.parent_id.clone()),
+                _ => return,
+            };
+            if new_name == name && new_parent_id == parent_id {
+                tree.inode_table.bind(ino, uploaded.id.clone());
+                if let Some(file) = tree.files.get_mut(&ino) {
+                    // the server may not report the size and hash until the
+                    // upload is processed, the uploaded ones are known
+                    *file = PikpakFile {
+                        name,
+                        parent_id,
+                        phase: "PHASE_TYPE_COMPLETE".to_string(),
+                        size: job.size.to_string(),
+                        hash: job.hash.clone(),
+                        ..uploaded
+                    };
+                }
+                return;
+            }
+            drop(tree);
+            // renamed or moved while waiting for upload
+            if new_name != name {
+                match self.drive.rename_file(&uploaded.id, &new_name) {
+                    Ok(renamed) => uploaded = renamed,
+                    Err(err) => {
+                        error!(file_name = %name, new_name = %new_name, error = %err, "rename uploaded file failed");
+                        return;
+                    }
+                }
+                name = new_name;
+            }
+            if new_parent_id != parent_id {
+                if let Err(err) = self.drive.move_file(&uploaded.id, &new_parent_id) {
+                    error!(file_name = %name, error = %err, "move uploaded file failed");
+                    return;
+                }
+                self.dir_cache.invalidate(&new_parent_id);
+                parent_id = new_parent_id;
+            }
         }
     }
 
+    /// Whether the folder `ino` has no children, always listed from the
+    /// server since removing a folder on PikPak removes everything in it
+    fn dir_is_empty(&self, ino: u64) -> Result<bool, Error> {
+        self.invalidate_dir(ino);
+        // the listing starts with `.` and `..`
+        Ok(self.readdir(ino, 0)?.len() <= 2)
+    }
 
     fn rename(
-        &mut self,
-        req: &Request,
+        &self,
         parent: u64,
         name: &OsStr,
         new_parent: u64,
         new_name: &OsStr,
         flags: u32,
-        reply: ReplyEmpty,
-    ) {
-        let file = match self.lookup(parent, name) {
-            Ok(attrs) => attrs,
-            Err(error_code) => {
-                reply.error(error_code.into());
-                return;
-            }
+    ) -> Result<(), Error> {
+        debug!(flags = flags, name = %Path::new(name).display(), new_name = %Path::new(new_name).display(), "rename");
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return Err(Error::NotSupported);
+        }
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let file = self.lookup(parent, name)?;
+        let target = match self.lookup(new_parent, new_name) {
+            Ok(target) => Some(target),
+            Err(Error::ChildNotFound) => None,
+            Err(e) => return Err(e),
         };
-
-        debug!(flags = flags, name=name.to_string_lossy().to_string(), "rename");
-        let file_id = self.files.get(&file.ino).unwrap().id.clone();
-        if parent == new_parent {
-            let res:PikpakFile = match self.drive.rename_file(&file_id, &new_name.to_string_lossy()) {
-                Ok(res) => {
-                     reply.ok();
-                     return;
-                },
-                Err(error_code) => {
-                    debug!("rename error: {:?}", error_code);
-                    reply.error(libc::EFAULT);
-                    return;
+        if let Some(target) = &target {
+            if target.ino == file.ino {
+                return Ok(());
+            }
+            if flags & libc::RENAME_NOREPLACE != 0 {
+                return Err(Error::Exists);
+            }
+            match (file.kind, target.kind) {
+                (FileType::Directory, FileType::Directory) if !self.dir_is_empty(target.ino)? => {
+                    return Err(Error::NotEmpty)
                 }
-            };
+                (FileType::Directory, FileType::Directory) => {}
+                (FileType::Directory, _) => return Err(Error::NotDirectory),
+                (_, FileType::Directory) => return Err(Error::IsDirectory),
+                _ => {}
+            }
+        }
+        if self.tree.lock().is_ancestor(file.ino, new_parent) {
+            // a folder can't be moved into itself
+            return Err(Error::InvalidArgument);
+        }
+        let file_id = self.file_id(file.ino)?;
+        let new_parent_id = self.file_id(new_parent)?;
+        self.invalidate_dir(parent);
+        self.invalidate_dir(new_parent);
+        self.negative_cache.invalidate(new_parent, new_name);
+
+        let mut trashed = None;
+        if let Some(target) = &target {
+            // PikPak keeps both files if the name is taken, the target goes
+            // first, to the trash so it can be restored if the rename fails
+            let target_id = self.file_id(target.ino)?;
+            if !target_id.is_empty() {
+                self.drive.trash_file(&target_id).map_err(|err| {
+                    error!(file_name = %Path::new(new_name).display(), error = %err, "trash rename target failed");
+                    Error::ApiCallFailed
+                })?;
+                self.dir_cache.invalidate(&target_id);
+                trashed = Some(target_id);
+            }
+        }
 
-        } else {
-            if name != new_name {
-                let res:PikpakFile = match self.drive.rename_file(&file_id, &new_name.to_string_lossy()) {
-                    Ok(res) => res,
-                    Err(error_code) => {
-                        debug!("rename newname error: to new parent failed {:?}", error_code);
-                        reply.error(libc::EFAULT);
-                        return;
+        let new_parent_id = (parent != new_parent).then_some(new_parent_id.as_str());
+        let renamed = match self.rename_on_server(&file_id, name, new_name, new_parent_id) {
+            Ok(renamed) => renamed,
+            Err(err) => {
+                if let Some(target_id) = trashed {
+                    if let Err(err) = self.drive.untrash_file(&target_id) {
+                        error!(file_name = %Path::new(new_name).display(), error = %err, "restore rename target failed");
                     }
-                };
+                }
+                self.invalidate_dir(parent);
+                self.invalidate_dir(new_parent);
+                return Err(err);
             }
-            let new_parent = self.files.get(&new_parent).unwrap().id.clone();
-            let res:PikpakFile = match self.drive.move_file(&file_id, &new_parent) {
-                Ok(res) => {
-                    reply.ok();
-                    return;
-                },
-                Err(error_code) => {
-                    debug!("rename error: {:?}", error_code);
-                    reply.error(libc::EFAULT);
-                    return;
+        };
+        let mut tree = self.tree.lock();
+        if target.is_some() {
+            tree.remove_child(new_parent, new_name);
+        }
+        tree.move_child(parent, name, new_parent, new_name, renamed);
+        Ok(())
+    }
+
+    /// Rename `file_id` to `new_name` and move it into `new_parent_id` on the
+    /// server, the rename is undone if the move fails. A file waiting for
+    /// upload is renamed on the server once uploaded.
+    fn rename_on_server(
+        &self,
+        file_id: &str,
+        name: &OsStr,
+        new_name: &OsStr,
+        new_parent_id: Option<&str>,
+    ) -> Result<Option<PikpakFile>, Error> {
+        if file_id.is_empty() {
+            return Ok(None);
+        }
+        let mut renamed = None;
+        if name != new_name {
+            let file = self
+                .drive
+                .rename_file(file_id, &new_name.to_string_lossy())
+                .map_err(|err| {
+                    error!(file_name = %Path::new(name).display(), error = %err, "rename failed");
+                    Error::ApiCallFailed
+                })?;
+            renamed = Some(file);
+        }
+        if let Some(new_parent_id) = new_parent_id {
+            if let Err(err) = self.drive.move_file(file_id, new_parent_id) {
+                error!(file_name = %Path::new(name).display(), error = %err, "move failed");
+                if renamed.is_some() {
+                    if let Err(err) = self.drive.rename_file(file_id, &name.to_string_lossy()) {
+                        error!(file_name = %Path::new(new_name).display(), error = %err, "undo rename failed");
+                    }
                 }
-            };
-    
+                return Err(Error::ApiCallFailed);
+            }
         }
-        reply.ok();
+        Ok(renamed)
     }
 
+    /// Copy a whole uploaded file on the server into a new empty file, other
+    /// copies fall back to reads and writes
     fn copy_file_range(
-        &mut self,
-        _req: &Request<'_>,
+        &self,
         src_inode: u64,
-        src_fh: u64,
         src_offset: i64,
         dest_inode: u64,
-        dest_fh: u64,
         dest_offset: i64,
         size: u64,
-        _flags: u32,
-        reply: ReplyWrite,
-    ) {
-        debug!(
-            "copy_file_range() called with src ({}, {}, {}) dest ({}, {}, {}) size={}",
-            src_fh, src_inode, src_offset, dest_fh, dest_inode, dest_offset, size
-        );
-
-
-        let src_file = match self.files.get(&src_inode) {
-            Some(file) => file,
-            None => {
-                reply.error(libc::EFAULT);
-                return;
-            }
+    ) -> Result<u32, Error> {
+        let (src, dest) = {
+            let tree = self.tree.lock();
+            let src = tree.files.get(&src_inode).cloned().ok_or(Error::NoEntry)?;
+            let dest = tree.files.get(&dest_inode).cloned().ok_or(Error::NoEntry)?;
+            (src, dest)
         };
+        let src_size = src.size.parse::<u64>().unwrap_or_default();
+        if src_offset as u64 >= src_size {
+            return Ok(0);
+        }
+        // `create` attaches an empty staging file, it only counts once
+        // something is written
+        let dest_written = self
+            .upload_states
+            .lock()
+            .values()
+            .any(|upload_state| {
+                let upload_state = upload_state.lock();
+                upload_state.ino == dest_inode
+                    && upload_state.staging.as_ref().map(|staging| staging.size() > 0).unwrap_or(false)
+            });
+        if src.id.is_empty()
+            || !dest.id.is_empty()
+            || dest.size != "0"
+            || dest_written
+            || self.tree.lock().is_queued(dest_inode)
+            || src_offset != 0
+            || dest_offset != 0
+            || size < src_size
+            || src_size > u32::MAX as u64
+        {
+            return Err(Error::NotSupported);
+        }
 
-        let src_file_id = src_file.id.clone();
-        let dest_file_id = self.files.get(&src_inode).unwrap().id.clone();
-        let res:TaskResponse = match self.drive.copy_file(&src_file_id, &dest_file_id){
-            Ok(res) => {
-                reply.written(src_file.size.parse::<u32>().unwrap());
-                return;
-            }
-            Err(error_code) => {
-                debug!("copy error: {:?}", error_code);
-                reply.error(libc::EFAULT);
-                return;
+        let copied = self.copy_file(&src, &dest.parent_id)?;
+        let copied = if copied.name != dest.name {
+            self.drive
+                .rename_file(&copied.id, &dest.name)
+                .map_err(|err| {
+                    error!(file_name = %dest.name, error = %err, "rename copied file failed");
+                    Error::ApiCallFailed
+                })?
+        } else {
+            copied
+        };
+        debug!(src = %src.name, dest = %dest.name, id = %copied.id, "copied on server");
+        // the empty staging files would be uploaded over the copy on release
+        let upload_states: Vec<_> = self.upload_states.lock().values().cloned().collect();
+        for upload_state in upload_states {
+            let mut upload_state = upload_state.lock();
+            if upload_state.ino == dest_inode
+                && upload_state.staging.as_ref().map(|staging| staging.size() == 0).unwrap_or(false)
+            {
+                upload_state.staging = None;
             }
+        }
+        let mut tree = self.tree.lock();
+        tree.inode_table.bind(dest_inode, copied.id.clone());
+        tree.files.insert(
+            dest_inode,
+            PikpakFile {
+                name: dest.name,
+                parent_id: dest.parent_id,
+                ..copied
+            },
+        );
+        Ok(src_size as u32)
+    }
+
+    /// Copy `file` into the folder `parent_id` and return the copy
+    fn copy_file(&self, file: &PikpakFile, parent_id: &str) -> Result<PikpakFile, Error> {
+        let list = || {
+            self.dir_cache.invalidate(parent_id);
+            self.list_dir(parent_id).map(|(files, _)| files)
         };
+        let existing: Vec<String> = list()?.into_iter().map(|f| f.id).collect();
+        self.drive.copy_file(&file.id, parent_id).map_err(|err| {
+            error!(file_name = %file.name, error = %err, "copy file failed");
+            Error::ApiCallFailed
+        })?;
+        // the copy keeps the name of the original, it's told apart from
+        // existing files with the same name by its id
+        for _ in 0..COPY_LIST_ATTEMPTS {
+            let copied = list()?
+                .into_iter()
+                .find(|f| f.name == file.name && !existing.contains(&f.id));
+            if let Some(copied) = copied {
+                return Ok(copied);
+            }
+            std::thread::sleep(COPY_LIST_INTERVAL);
+        }
+        error!(file_name = %file.name, "copied file not found");
+        Err(Error::ApiCallFailed)
     }
 
     //目录操作
-    fn mkdir(
-        &mut self,
-        req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mut mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        debug!("mkdir() called with {:?} {:?} {:o}", parent, name, mode);
+    fn mkdir(&self, parent: u64, name: &OsStr, reply: ReplyEntry) {
         if self.lookup(parent, name).is_ok() {
             reply.error(libc::EEXIST);
             return;
         }
-        let parent_file = match self.files.get(&parent).ok_or(Error::NoEntry){
-            Ok(file) => file,
+        let parent_file_id = match self.file_id(parent) {
+            Ok(file_id) => file_id,
             Err(e) => {
                 reply.error(Error::ParentNotFound.into());
                 return;
             }
         };
         let new_folder_name = name.to_string_lossy().to_string();
-        let parent_file_id = parent_file.id.clone();
         let new_dir_res:CreateFolderResponse = match self.drive.create_folder(&parent_file_id,&new_folder_name) {
             Ok(res) => res,
             Err(error_code) => {
@@ -671,54 +1341,42 @@ impl Filesystem for PikpakDriveFileSystem {
             }
         };
         let new_dir = new_dir_res.file;
+        self.dir_cache.invalidate(&parent_file_id);
+        self.negative_cache.invalidate(parent, name);
 
-        let new_inode = self.next_inode();
+        let mut tree = self.tree.lock();
+        let new_inode = tree.inode_table.get_or_insert(&new_dir.id);
+        tree.inode_table.lookup(new_inode);
         let attrs = new_dir.to_file_attr(new_inode);
+        tree.add_child(parent, new_inode, new_dir);
 
-        reply.entry(&TTL, &attrs, 0);
+        reply.entry(&self.entry_timeout, &attrs, 0);
     }
 
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!("rmdir() called with {:?} {:?}", parent, name);
-
-        let file = match self.lookup(parent, name) {
-            Ok(file) => file,
-            Err(e) => {
-                reply.error(e.into());
-                return;
-            }
-        };
-        let file_id = self.files.get(&file.ino).unwrap().id.clone();
-
-        let res:TaskResponse = match self.drive.remove_file(&file_id) {
-            Ok(res) => {
-                 reply.ok();
-                 return;
-            },
-            Err(error_code) => {
-                debug!("delete_folder error: {:?}", error_code);
-                reply.error(libc::EFAULT);
-                return;
-            }
-        };
-        reply.ok()
+    fn rmdir(&self, parent: u64, name: &OsStr) -> Result<(), Error> {
+        let file = self.lookup(parent, name)?;
+        if file.kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+        if !self.dir_is_empty(file.ino)? {
+            return Err(Error::NotEmpty);
+        }
+        let file_id = self.file_id(file.ino)?;
+        self.drive.remove_file(&file_id).map_err(|err| {
+            error!(file_name = %Path::new(name).display(), error = %err, "remove folder failed");
+            Error::ApiCallFailed
+        })?;
+        self.invalidate_dir(parent);
+        self.dir_cache.invalidate(&file_id);
+        self.tree.lock().remove_child(parent, name);
+        Ok(())
     }
 
  
 
     // 文件操作
-    fn create(
-        &mut self,
-        req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mut mode: u32,
-        _umask: u32,
-        flags: i32,
-        reply: ReplyCreate,
-    ) {
-        debug!("create() called with {:?} {:?}", parent, name);
+    fn create(&self, parent: u64, name: &OsStr, flags: i32, reply: ReplyCreate) {
         // 忽略 macOS 上的一些特殊文件
         let file_name = name.to_string_lossy();
         if file_name == ".DS_Store" || file_name.starts_with("._") {
@@ -731,10 +1389,31 @@ impl Filesystem for PikpakDriveFileSystem {
             return;
         }
 
-        let new_file_inode = self.next_inode();
-        let file_inode = Inode::new(new_file_inode);
-        let mut parent_inode = self.inodes.get(&parent).ok_or(Error::NoEntry).unwrap().clone();
-        let parent_file = match self.files.get(&parent).ok_or(Error::NoEntry){
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            // Exactly one access mode flag must be specified
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        let fh = self.allocate_next_file_handle(read, write);
+        let new_file_inode = self.tree.lock().inode_table.allocate();
+        // an empty file is uploaded too if nothing is written before release
+        let staging = match self.create_staging(new_file_inode, fh) {
+            Ok(staging) => staging,
+            Err(e) => {
+                reply.error(e.into());
+                return;
+            }
+        };
+
+        let mut tree = self.tree.lock();
+        let file_inode = Inode::new(parent);
+        let mut parent_inode = tree.inodes.get(&parent).ok_or(Error::NoEntry).unwrap().clone();
+        let parent_file = match tree.files.get(&parent).ok_or(Error::NoEntry){
             Ok(file) => file,
             Err(e) => {
                 reply.error(Error::ParentNotFound.into());
@@ -745,11 +1424,6 @@ impl Filesystem for PikpakDriveFileSystem {
         let parent_file_id = parent_file.id.clone();
         let file_name =name.to_string_lossy().to_string();
         let now = SystemTime::now();
-        let hash_str = format!("{}{}",&file_name,now.duration_since(UNIX_EPOCH).unwrap().as_secs());
-        let mut hasher = Sha1::default();
-        hasher.update(hash_str.as_bytes());
-        let hash_code = hasher.finalize();
-        let file_hash = format!("{:X}",&hash_code);
         let file = PikpakFile {
             name: file_name,
             kind: "drive#file".to_string(),
@@ -763,99 +1437,123 @@ impl Filesystem for PikpakDriveFileSystem {
             mime_type: "".to_string(),
             web_content_link: "".to_string(),
             medias:Vec::new(),
-            hash:Some(file_hash),
+            // content hash is computed while the file is written
+            hash:None,
         };
-        self.files.insert(new_file_inode, file.clone());
-        self.inodes.entry(new_file_inode).or_insert_with(|| Inode::new(new_file_inode));
+        self.dir_cache.invalidate(&file.parent_id);
+        self.negative_cache.invalidate(parent, name);
+        tree.files.insert(new_file_inode, file.clone());
         parent_inode.add_child(name.to_os_string(), new_file_inode);
-        self.inodes.insert(new_file_inode, file_inode);
-        self.inodes.insert(parent, parent_inode);
+        tree.inodes.insert(new_file_inode, file_inode);
+        tree.inodes.insert(parent, parent_inode);
 
-        let (read, write) = match flags & libc::O_ACCMODE {
-            libc::O_RDONLY => (true, false),
-            libc::O_WRONLY => (false, true),
-            libc::O_RDWR => (true, true),
-            // Exactly one access mode flag must be specified
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
-            }
-        };
         let attrs = file.to_file_attr(new_file_inode);
-        reply.created(
-            &Duration::new(0, 0),
-            &attrs.into(),
-            0,
-            self.allocate_next_file_handle(read, write),
-            0,
-        );
+        tree.inode_table.lookup(new_file_inode);
+        drop(tree);
+        if let Err(e) = self.stage(new_file_inode, Some(fh), staging) {
+            reply.error(e.into());
+            return;
+        }
+        reply.created(&self.entry_timeout, &attrs, 0, fh, 0);
 
     }
 
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        debug!("unlink() called with {:?} {:?}", parent, name);
-        let file = match self.lookup(parent, name) {
-            Ok(file) => file,
-            Err(e) => {
-                reply.error(e.into());
-                return;
-            }
-        };
-        let file_id = self.files.get(&file.ino).unwrap().id.clone();
-        let res:TaskResponse = match self.drive.remove_file(&file_id) {
-            Ok(res) => {
-                 reply.ok();
-                 return;
-            },
-            Err(error_code) => {
-                debug!("delete_folder error: {:?}", error_code);
-                reply.error(libc::EFAULT);
-                return;
-            }
-        };
-        reply.ok()
+    fn unlink(&self, parent: u64, name: &OsStr) -> Result<(), Error> {
+        let file = self.lookup(parent, name)?;
+        if file.kind == FileType::Directory {
+            return Err(Error::IsDirectory);
+        }
+        // a file waiting for upload is removed from the server once uploaded
+        let file_id = self.file_id(file.ino)?;
+        if !file_id.is_empty() {
+            self.drive.remove_file(&file_id).map_err(|err| {
+                error!(file_name = %Path::new(name).display(), error = %err, "remove file failed");
+                Error::ApiCallFailed
+            })?;
+        }
+        self.invalidate_dir(parent);
+        self.tree.lock().remove_child(parent, name);
+        Ok(())
     }
 
-    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
-        debug!("flush() called with {:?} {:?}", ino, fh);
-        match  self.prepare_for_upload(ino, fh) {
-            Ok(true) => {
-                self.maybe_upload_chunk(true, ino, fh);
-                reply.ok();
-            }
-            Ok(false) => {
-                reply.error(libc::ENOENT);
-            }
-            Err(err) => {
-                reply.error(libc::ENOENT);
-            }
+    /// Open `ino` for reading, files still waiting for upload are read from
+    /// the written data until they're uploaded
+    fn open(&self, ino: u64) -> Result<Option<u64>, Error> {
+        let (file_id, file_name, file_size, version) = self
+            .tree
+            .lock()
+            .files
+            .get(&ino)
+            .map(|f| (f.id.clone(), f.name.clone(), f.size.parse::<u64>().unwrap(), f.content_version()))
+            .ok_or(Error::NoEntry)?;
+        debug!(inode = ino, name = %file_name, "open file");
+        if file_name == ".DS_Store" || file_name.starts_with("._") {
+            return Ok(None);
         }
+        let fh = self.next_fh();
+        self.file_cache.open(fh, file_id, file_size, version);
+        Ok(Some(fh))
     }
 
-    fn write(
-            &mut self,
-            _req: &Request<'_>,
-            ino: u64,
-            fh: u64,
-            offset: i64,
-            data: &[u8],
-            write_flags: u32,
-            flags: i32,
-            lock_owner: Option<u64>,
-            reply: ReplyWrite,
-        ) {
-        debug!("write() called with {:?} {:?}", offset, data.len());
-        match  self.prepare_for_upload(ino, fh) {
+    fn release(&self, ino: u64, fh: u64, reply: ReplyEmpty) {
+        self.file_cache.release(fh);
+        let upload_state = self.upload_states.lock().remove(&fh);
+        let res = match upload_state {
+            Some(upload_state) => self.enqueue_upload(&mut upload_state.lock(), ino),
+            None => Ok(()),
+        };
+        match res {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.into()),
+        }
+    }
+
+    fn write(&self, ino: u64, fh: u64, offset: i64, data: &[u8], reply: ReplyWrite) {
+        let upload_state = self.upload_state(fh);
+        let mut upload_state = upload_state.lock();
+        match self.prepare_for_upload(ino) {
             Ok(true) => {
-                self.upload_state.buffer.extend_from_slice(&data);
-                let mut upload_size = self.upload_state.size;
-                if data.len() + offset as usize > upload_size as usize {
-                    upload_size = (data.len() + offset as usize) as u64;
+                // data is spooled to a staging file and queued for upload
+                // once the file is closed
+                if upload_state.staging.is_none() {
+                    match self.create_staging(ino, fh) {
+                        Ok(staging) => {
+                            upload_state.ino = ino;
+                            upload_state.staging = Some(staging);
+                            upload_state.size_limit = self.upload_size_limit();
+                        }
+                        Err(err) => {
+                            reply.error(err.into());
+                            return;
+                        }
+                    }
+                }
+                if offset as u64 + data.len() as u64 > upload_state.size_limit {
+                    error!(inode = ino, limit = upload_state.size_limit, "file exceeds the upload size limit");
+                    reply.error(Error::FileTooLarge.into());
+                    return;
+                }
+                let staging = upload_state.staging.as_mut().unwrap();
+                match staging.write_at(offset as u64, data) {
+                    Ok(()) => {
+                        let size = staging.size().to_string();
+                        let mut tree = self.tree.lock();
+                        if let Some(file) = tree.files.get_mut(&ino) {
+                            file.size = size;
+                            file.modified_time = DateTime::new(SystemTime::now());
+                        }
+                        // writing overrides a modification time set before
+                        if let Some(inode) = tree.inodes.get_mut(&ino) {
+                            inode.attr.mtime = None;
+                        }
+                        reply.written(data.len() as u32 )
+                    }
+                    Err(err) => {
+                        error!(inode = ino, error = %err, "write staging file failed");
+                        reply.error(libc::EIO);
+                    }
                 }
-                self.upload_state.size = upload_size;
-                self.maybe_upload_chunk(false, ino, fh);
-                reply.written(data.len() as u32 );
             }
             Ok(false) => {
                 reply.error(libc::ENOENT);
@@ -865,22 +1563,351 @@ impl Filesystem for PikpakDriveFileSystem {
             }
         }
     }
-
 }
 
-impl PikpakFile {
-    fn to_file_attr(&self, ino: u64) -> FileAttr {
-        //let kind = self.kind.into();
-        let kind = if self.kind.eq("drive#folder"){
-            FileType::Directory
-        }else{
-            FileType::RegularFile
-        };
-        
-        let perm = if matches!(kind, FileType::Directory) {
-            0o755
-        } else {
-            0o644
+impl<D: DriveBackend> Filesystem for PikpakDriveFileSystem<D> {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        _config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        if let Err(e) = self.inner.init() {
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        self.inner.tree.lock().inode_table.persist();
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let dirname = Path::new(name);
+        // 忽略 macOS 上的一些特殊文件
+        debug!(parent = parent, name = %dirname.display(), "lookup");
+        let inner = self.inner.clone();
+        let name = name.to_os_string();
+        self.pool.execute(move || match inner.lookup(parent, &name) {
+            Ok(attr) => {
+                inner.tree.lock().inode_table.lookup(attr.ino);
+                reply.entry(&inner.entry_timeout, &attr, 0)
+            }
+            Err(Error::ChildNotFound) if !inner.negative_timeout.is_zero() => {
+                // an entry with inode 0 is cached by the kernel as a negative entry
+                reply.entry(&inner.negative_timeout, &negative_entry_attr(), 0)
+            }
+            Err(e) => reply.error(e.into()),
+        });
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        debug!(inode = ino, nlookup = nlookup, "forget");
+        let mut tree = self.inner.tree.lock();
+        if tree.inode_table.forget(ino, nlookup) == 0 {
+            if tree.is_attached(ino) {
+                tree.evict(ino);
+            } else {
+                tree.remove_inode(ino);
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!(inode = ino, mode = ?mode, uid = ?uid, gid = ?gid, size = ?size, fh = ?fh, "setattr");
+        let time = |time: TimeOrNow| match time {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => SystemTime::now(),
+        };
+        let changes = AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime: atime.map(time),
+            mtime: mtime.map(time),
+        };
+        let inner = self.inner.clone();
+        let job = move || match inner.setattr(ino, fh, changes) {
+            Ok(attr) => reply.attr(&inner.attr_timeout, &attr),
+            Err(e) => reply.error(e.into()),
+        };
+        // ordered with the writes to the same handle
+        match fh {
+            Some(fh) => self.pool.execute_ordered(fh, job),
+            None => self.pool.execute(job),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        debug!("statfs");
+        let inner = self.inner.clone();
+        self.pool.execute(move || inner.statfs(reply));
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let tree = self.inner.tree.lock();
+        if let Some(file) = tree.files.get(&ino) {
+            debug!(inode = ino, name = %file.name, "getattr");
+            reply.attr(&self.inner.attr_timeout, &tree.file_attr(ino).unwrap())
+        } else {
+            debug!(inode = ino, "getattr");
+            reply.error(libc::ENOENT);
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!(inode = ino, offset = offset, "readdir");
+        let inner = self.inner.clone();
+        self.pool.execute(move || match inner.readdir(ino, offset) {
+            Ok(entries) => {
+                // Offset of 0 means no offset.
+                // Non-zero offset means the passed offset has already been seen,
+                // and we should start after it.
+                let offset_add = if offset == 0 { 0 } else { offset + 1 };
+                for (i, (ino, kind, name)) in entries.into_iter().enumerate() {
+                    let buffer_full = reply.add(ino, offset_add + i as i64, kind, name);
+                    if buffer_full {
+                        break;
+                    }
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e.into()),
+        });
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        debug!(inode = ino, "open");
+        match self.inner.open(ino) {
+            Ok(Some(fh)) => reply.opened(fh, 0),
+            // 忽略 macOS 上的一些特殊文件
+            Ok(None) => {}
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!(inode = ino, fh = fh, "release file");
+        let inner = self.inner.clone();
+        self.pool.execute_ordered(fh, move || inner.release(ino, fh, reply));
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        debug!(inode = ino, fh = fh, offset = offset, size = size, "read work here");
+        let inner = self.inner.clone();
+        self.pool.execute_ordered(fh, move || match inner.read(ino, fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e.into()),
+        });
+    }
+
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let inner = self.inner.clone();
+        let name = name.to_os_string();
+        let new_name = new_name.to_os_string();
+        self.pool.execute(move || {
+            match inner.rename(parent, &name, new_parent, &new_name, flags) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.into()),
+            }
+        });
+    }
+
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        src_inode: u64,
+        src_fh: u64,
+        src_offset: i64,
+        dest_inode: u64,
+        dest_fh: u64,
+        dest_offset: i64,
+        size: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            "copy_file_range() called with src ({}, {}, {}) dest ({}, {}, {}) size={}",
+            src_fh, src_inode, src_offset, dest_fh, dest_inode, dest_offset, size
+        );
+        let inner = self.inner.clone();
+        self.pool.execute_ordered(dest_fh, move || {
+            match inner.copy_file_range(src_inode, src_offset, dest_inode, dest_offset, size) {
+                Ok(written) => reply.written(written),
+                Err(e) => reply.error(e.into()),
+            }
+        });
+    }
+
+    //目录操作
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        debug!("mkdir() called with {:?} {:?} {:o}", parent, name, mode);
+        let inner = self.inner.clone();
+        let name = name.to_os_string();
+        self.pool.execute(move || inner.mkdir(parent, &name, reply));
+    }
+
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("rmdir() called with {:?} {:?}", parent, name);
+        let inner = self.inner.clone();
+        let name = name.to_os_string();
+        self.pool.execute(move || match inner.rmdir(parent, &name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        });
+    }
+
+    // 文件操作
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!("create() called with {:?} {:?}", parent, name);
+        let inner = self.inner.clone();
+        let name = name.to_os_string();
+        self.pool.execute(move || inner.create(parent, &name, flags, reply));
+    }
+
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("unlink() called with {:?} {:?}", parent, name);
+        let inner = self.inner.clone();
+        let name = name.to_os_string();
+        self.pool.execute(move || match inner.unlink(parent, &name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        });
+    }
+
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush() called with {:?} {:?}", ino, fh);
+        // written data is uploaded in the background once the file is released
+        reply.ok();
+    }
+
+    fn write(
+            &mut self,
+            _req: &Request<'_>,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            data: &[u8],
+            _write_flags: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyWrite,
+        ) {
+        debug!("write() called with {:?} {:?}", offset, data.len());
+        let inner = self.inner.clone();
+        let data = data.to_vec();
+        self.pool.execute_ordered(fh, move || inner.write(ino, fh, offset, &data, reply));
+    }
+
+}
+
+/// Attributes of a negative lookup reply
+fn negative_entry_attr() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
+
+impl PikpakFile {
+    fn to_file_attr(&self, ino: u64) -> FileAttr {
+        //let kind = self.kind.into();
+        let kind = if self.kind.eq("drive#folder"){
+            FileType::Directory
+        }else{
+            FileType::RegularFile
+        };
+        
+        let perm = if matches!(kind, FileType::Directory) {
+            0o755
+        } else {
+            0o644
         };
         let nlink = if ino == FUSE_ROOT_ID { 2 } else { 1 };
         let uid = unsafe { libc::getuid() };
@@ -906,3 +1933,721 @@ impl PikpakFile {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::drive::memory::MemoryDrive;
+    use crate::upload_queue::UploadPhase;
+
+    /// Filesystem over a `MemoryDrive`, the working directory is removed on drop
+    struct TestMount {
+        fs: Option<PikpakDriveFileSystem<MemoryDrive>>,
+        drive: MemoryDrive,
+        workdir: PathBuf,
+    }
+
+    fn mount(drive: &MemoryDrive, workdir: &Path) -> PikpakDriveFileSystem<MemoryDrive> {
+        let config = VfsConfig {
+            workdir: Some(workdir.to_path_buf()),
+            cache_dir: None,
+            cache_max_size: 0,
+            read_buffer_size: 1024,
+            read_ahead: 0,
+            download_connections: 1,
+            download_retries: 0,
+            short_reads: false,
+            memory_cache_size: 1024 * 1024,
+            url_cache_size: 10,
+            url_cache_ttl: 60,
+            upload_buffer_size: 1024 * 1024,
+            upload_concurrency: 1,
+            dir_cache_ttl: 60,
+            dir_cache_size: 10,
+            attr_timeout: Duration::from_secs(1),
+            entry_timeout: Duration::from_secs(1),
+            negative_timeout: Duration::ZERO,
+            worker_threads: 1,
+            store_permissions: false,
+        };
+        let fs = PikpakDriveFileSystem::new(drive.clone(), config);
+        fs.inner.init().unwrap();
+        fs
+    }
+
+    /// Poll `done` for up to 10 seconds
+    fn wait_for(what: &str, done: impl Fn() -> bool) {
+        for _ in 0..1000 {
+            if done() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    impl TestMount {
+        fn new(name: &str, drive: MemoryDrive) -> Self {
+            let workdir = std::env::temp_dir().join(format!("pikpak-fuse-test-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&workdir);
+            let fs = mount(&drive, &workdir);
+            Self {
+                fs: Some(fs),
+                drive,
+                workdir,
+            }
+        }
+
+        /// Drop the filesystem without saving anything, like a crash, and
+        /// mount the working directory again
+        fn remount(&mut self) {
+            // stops the upload workers before the new queue loads the journals
+            self.fs = None;
+            self.fs = Some(mount(&self.drive, &self.workdir));
+        }
+
+        fn inner(&self) -> &Inner<MemoryDrive> {
+            &self.fs.as_ref().unwrap().inner
+        }
+
+        fn ino(&self, parent: u64, name: &str) -> u64 {
+            self.inner().lookup(parent, OsStr::new(name)).unwrap().ino
+        }
+
+        fn names(&self, parent_id: &str) -> Vec<String> {
+            self.drive.list_all(parent_id).unwrap().into_iter().map(|file| file.name).collect()
+        }
+
+        /// New file in `parent` that isn't on the server yet
+        fn add_file(&self, parent: u64, name: &str, size: usize) -> u64 {
+            let mut tree = self.inner().tree.lock();
+            let ino = tree.inode_table.allocate();
+            let mut file = PikpakFile::new_root();
+            file.kind = "drive#file".to_string();
+            file.id = String::new();
+            file.name = name.to_string();
+            file.parent_id = tree.files[&parent].id.clone();
+            file.size = size.to_string();
+            tree.add_child(parent, ino, file);
+            ino
+        }
+
+        /// Write `data` to a new file `name` in `parent` and queue its upload
+        fn write(&self, parent: u64, name: &str, data: &[u8]) -> u64 {
+            let inner = self.inner();
+            let ino = self.add_file(parent, name, data.len());
+            let mut staging = inner.create_staging(ino, 0).unwrap();
+            staging.write_at(0, data).unwrap();
+            inner.stage(ino, None, staging).unwrap();
+            ino
+        }
+
+        /// Create a file `name` in `parent` like `create` and write `data`
+        /// through the returned handle, it's uploaded on `release`
+        fn create(&self, parent: u64, name: &str, data: &[u8]) -> (u64, u64) {
+            let inner = self.inner();
+            let ino = self.add_file(parent, name, data.len());
+            let fh = inner.next_fh();
+            let mut staging = inner.create_staging(ino, fh).unwrap();
+            staging.write_at(0, data).unwrap();
+            inner.stage(ino, Some(fh), staging).unwrap();
+            (ino, fh)
+        }
+
+        /// Close a handle returned by `create` like `release`
+        fn release(&self, ino: u64, fh: u64) {
+            let inner = self.inner();
+            let upload_state = inner.upload_states.lock().remove(&fh).unwrap();
+            inner.enqueue_upload(&mut upload_state.lock(), ino).unwrap();
+        }
+
+        /// Write `data` to a new file `name` in `parent` and wait for its upload
+        fn upload(&self, parent: u64, name: &str, data: &[u8]) -> u64 {
+            let ino = self.write(parent, name, data);
+            wait_for(name, || !self.inner().file_id(ino).unwrap().is_empty());
+            ino
+        }
+    }
+
+    impl Drop for TestMount {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.workdir);
+        }
+    }
+
+    #[test]
+    fn reads_files_from_the_drive() {
+        let drive = MemoryDrive::new();
+        let file = drive.add_file("", "a", "hello world");
+        let dir = drive.add_folder("", "d");
+        drive.add_file(&dir.id, "b", "bbb");
+        let mount = TestMount::new("read", drive);
+        let fs = mount.inner();
+
+        let entries = fs.readdir(FUSE_ROOT_ID, 0).unwrap();
+        assert_eq!(
+            entries.iter().map(|(_, _, name)| name.as_str()).collect::<Vec<_>>(),
+            vec![".", "..", "a", "d"]
+        );
+        let a = fs.lookup(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+        assert_eq!(a.size, 11);
+        let d = fs.lookup(FUSE_ROOT_ID, OsStr::new("d")).unwrap();
+        assert_eq!(d.kind, FileType::Directory);
+        assert_eq!(fs.lookup(d.ino, OsStr::new("b")).unwrap().size, 3);
+        assert!(matches!(fs.lookup(FUSE_ROOT_ID, OsStr::new("c")), Err(Error::ChildNotFound)));
+
+        fs.file_cache.open(1, file.id.clone(), 11, file.content_version());
+        assert_eq!(fs.read(a.ino, 1, 0, 100).unwrap(), Bytes::from("hello world"));
+        assert_eq!(fs.read(a.ino, 1, 6, 3).unwrap(), Bytes::from("wor"));
+        assert!(fs.read(a.ino, 1, 11, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn readdir_keeps_inodes_stable() {
+        // one file per page
+        let drive = MemoryDrive::new().with_page_size(1);
+        drive.add_file("", "a", "aaa");
+        drive.add_folder("", "d");
+        let mount = TestMount::new("readdir", drive);
+
+        let first = mount.inner().readdir(FUSE_ROOT_ID, 0).unwrap();
+        assert_eq!(
+            first.iter().map(|(_, _, name)| name.as_str()).collect::<Vec<_>>(),
+            vec![".", "..", "a", "d"]
+        );
+        assert!(matches!(first[3].1, FileType::Directory));
+        let a = mount.ino(FUSE_ROOT_ID, "a");
+        assert_eq!(first[2].0, a);
+
+        // a file added on the server shows up without renumbering the others
+        mount.drive.add_file("", "b", "bbb");
+        mount.inner().invalidate_dir(FUSE_ROOT_ID);
+        let second = mount.inner().readdir(FUSE_ROOT_ID, 0).unwrap();
+        let inodes = second
+            .iter()
+            .map(|(ino, _, name)| (name.as_str(), *ino))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(inodes["a"], a);
+        assert_eq!(inodes["d"], first[3].0);
+        assert!(inodes.contains_key("b"));
+    }
+
+    #[test]
+    fn forgotten_inodes_are_evicted() {
+        let drive = MemoryDrive::new();
+        let d = drive.add_folder("", "d");
+        drive.add_file(&d.id, "a", "aaa");
+        drive.add_file(&d.id, "b", "bbb");
+        let mount = TestMount::new("evict", drive);
+        let inner = mount.inner();
+        let d = mount.ino(FUSE_ROOT_ID, "d");
+        let a = mount.ino(d, "a");
+        let b = mount.ino(d, "b");
+
+        inner.tree.lock().evict(a);
+        assert!(!inner.tree.lock().files.contains_key(&a));
+        // found again under the same inode
+        assert_eq!(mount.ino(d, "a"), a);
+        assert_eq!(mount.ino(d, "b"), b);
+        // folders with loaded children stay
+        inner.tree.lock().evict(d);
+        assert!(inner.tree.lock().files.contains_key(&d));
+    }
+
+    #[test]
+    fn listings_are_cached_until_invalidated() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", "a", "aaa");
+        let mount = TestMount::new("dir-cache", drive);
+        let names = || -> Vec<String> {
+            let entries = mount.inner().readdir(FUSE_ROOT_ID, 0).unwrap();
+            entries.into_iter().skip(2).map(|(_, _, name)| name).collect()
+        };
+        assert_eq!(names(), vec!["a"]);
+
+        mount.drive.add_file("", "b", "bbb");
+        assert_eq!(names(), vec!["a"]);
+        mount.inner().invalidate_dir(FUSE_ROOT_ID);
+        assert_eq!(names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn inodes_survive_remount() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", "a", "aaa");
+        let dir = drive.add_folder("", "d");
+        drive.add_file(&dir.id, "b", "bbb");
+        let mut mount = TestMount::new("remount", drive);
+        let a = mount.ino(FUSE_ROOT_ID, "a");
+        let d = mount.ino(FUSE_ROOT_ID, "d");
+        let b = mount.ino(d, "b");
+
+        // saved on unmount
+        mount.inner().tree.lock().inode_table.persist();
+        mount.remount();
+        assert_eq!(mount.ino(FUSE_ROOT_ID, "d"), d);
+        assert_eq!(mount.ino(d, "b"), b);
+        assert_eq!(mount.ino(FUSE_ROOT_ID, "a"), a);
+
+        // new files don't reuse a persisted number
+        mount.drive.add_file("", "c", "ccc");
+        mount.inner().invalidate_dir(FUSE_ROOT_ID);
+        mount.inner().readdir(FUSE_ROOT_ID, 0).unwrap();
+        let c = mount.ino(FUSE_ROOT_ID, "c");
+        assert!(![a, b, d].contains(&c));
+    }
+
+    #[test]
+    fn upload_size_is_limited_by_free_space() {
+        let drive = MemoryDrive::new().with_total_size(100);
+        drive.add_file("", "a", vec![0; 40]);
+        let mount = TestMount::new("upload-limit", drive);
+        assert_eq!(mount.inner().upload_size_limit(), 60);
+
+        // no quota reported
+        let mount = TestMount::new("upload-no-limit", MemoryDrive::new().with_total_size(0));
+        assert_eq!(mount.inner().upload_size_limit(), MAX_UPLOAD_SIZE);
+    }
+
+    #[test]
+    fn quota_is_cached() {
+        let total = 1024 * 1024;
+        let drive = MemoryDrive::new().with_total_size(total);
+        drive.add_file("", "a", "aaa");
+        let mount = TestMount::new("quota", drive);
+        assert_eq!(mount.inner().quota().unwrap(), (3, total));
+        mount.drive.add_file("", "b", "bbb");
+        assert_eq!(mount.inner().quota().unwrap(), (3, total));
+
+        // the last known quota stands in when fetching it fails
+        let expired = Instant::now() - QUOTA_CACHE_TTL;
+        *mount.inner().quota.lock() = Some((expired, (3, total)));
+        mount.drive.fail("get_quota", 1);
+        assert_eq!(mount.inner().quota().unwrap(), (3, total));
+        assert_eq!(mount.inner().quota().unwrap(), (6, total));
+        let mount = TestMount::new("quota-failed", MemoryDrive::new());
+        mount.drive.fail("get_quota", 1);
+        assert!(mount.inner().quota().is_err());
+    }
+
+    #[test]
+    fn uploads_written_file() {
+        let mount = TestMount::new("upload", MemoryDrive::new());
+        let ino = mount.upload(FUSE_ROOT_ID, "new", b"hello");
+        let file = mount.drive.find("", "new").unwrap();
+        assert_eq!(mount.inner().file_id(ino).unwrap(), file.id);
+        assert_eq!(mount.drive.content(&file.id).unwrap(), Bytes::from("hello"));
+        assert_eq!(mount.ino(FUSE_ROOT_ID, "new"), ino);
+
+        // known content is created without an upload
+        mount.upload(FUSE_ROOT_ID, "copy", b"hello");
+        let copy = mount.drive.find("", "copy").unwrap();
+        assert_ne!(copy.id, file.id);
+        assert_eq!(mount.drive.content(&copy.id).unwrap(), Bytes::from("hello"));
+
+        // an empty file is uploaded too
+        mount.upload(FUSE_ROOT_ID, "empty", b"");
+        let file = mount.drive.find("", "empty").unwrap();
+        assert_eq!(mount.drive.content(&file.id).unwrap_or_default(), Bytes::new());
+    }
+
+    #[test]
+    fn created_files_are_uploaded() {
+        let mount = TestMount::new("create", MemoryDrive::new());
+        let inner = mount.inner();
+
+        // nothing written before release
+        let (ino, fh) = mount.create(FUSE_ROOT_ID, "empty", b"");
+        mount.release(ino, fh);
+        wait_for("empty", || !inner.file_id(ino).unwrap().is_empty());
+        let file = mount.drive.find("", "empty").unwrap();
+        assert_eq!(mount.drive.content(&file.id).unwrap_or_default(), Bytes::new());
+
+        // only extended through the handle
+        let (ino, fh) = mount.create(FUSE_ROOT_ID, "zeros", b"");
+        inner.truncate(ino, Some(fh), 4).unwrap();
+        mount.release(ino, fh);
+        wait_for("zeros", || !inner.file_id(ino).unwrap().is_empty());
+        let file = mount.drive.find("", "zeros").unwrap();
+        assert_eq!(mount.drive.content(&file.id).unwrap(), Bytes::from(&b"\0\0\0\0"[..]));
+    }
+
+    #[test]
+    fn resumed_uploads_keep_to_their_files() {
+        let drive = MemoryDrive::new();
+        let d = drive.add_folder("", "d");
+        let mut mount = TestMount::new("resume", drive);
+        let d_ino = mount.ino(FUSE_ROOT_ID, "d");
+        // the first attempt of both uploads and the first one after the restart fail
+        mount.drive.fail("create_file_with_proof", 4);
+        let one = mount.write(FUSE_ROOT_ID, "one", b"first");
+        mount.write(FUSE_ROOT_ID, "two", b"second");
+        let attempted = |mount: &TestMount, attempts: u32| {
+            let status = mount.inner().upload_queue.status();
+            status.len() == 2 && status.iter().all(|upload| upload.attempts >= attempts)
+        };
+        wait_for("failed uploads", || attempted(&mount, 1));
+
+        // the inode numbers of the pending files weren't saved
+        mount.remount();
+        assert_eq!(mount.ino(FUSE_ROOT_ID, "d"), d_ino);
+        wait_for("resumed uploads", || attempted(&mount, 2));
+        // reuses the number of `one` and stays pending while `one` is resumed
+        mount.drive.remove_file(&d.id).unwrap();
+        let three = mount.write(d_ino, "three", b"third");
+        assert_eq!(three, one);
+
+        wait_for("resumed uploads", || mount.inner().upload_queue.status().len() == 1);
+        assert_eq!(mount.names(""), vec!["one", "two"]);
+        for (name, content) in [("one", "first"), ("two", "second")] {
+            let file = mount.drive.find("", name).unwrap();
+            assert_eq!(mount.drive.content(&file.id).unwrap(), Bytes::from(content));
+        }
+        assert_eq!(mount.inner().file_id(three).unwrap(), "");
+    }
+
+    #[test]
+    fn reads_files_waiting_for_upload() {
+        let mount = TestMount::new("read-pending", MemoryDrive::new());
+        let inner = mount.inner();
+        mount.drive.fail("create_file_with_proof", usize::MAX);
+        let queued = mount.write(FUSE_ROOT_ID, "queued", b"hello world");
+        let fh = inner.open(queued).unwrap().unwrap();
+        wait_for("failed upload", || inner.upload_queue.status()[0].attempts > 0);
+        assert_eq!(inner.read(queued, fh, 0, 100).unwrap(), Bytes::from("hello world"));
+        assert_eq!(inner.read(queued, fh, 6, 3).unwrap(), Bytes::from("wor"));
+
+        // still being written through another handle
+        let (writing, _) = mount.create(FUSE_ROOT_ID, "writing", b"data");
+        let read_fh = inner.open(writing).unwrap().unwrap();
+        assert_eq!(inner.read(writing, read_fh, 0, 100).unwrap(), Bytes::from("data"));
+
+        // the handle opened before the upload reads the uploaded file
+        mount.drive.fail("create_file_with_proof", 0);
+        wait_for("upload", || !inner.file_id(queued).unwrap().is_empty());
+        wait_for("uploaded data removed", || inner.upload_queue.status().is_empty());
+        assert_eq!(inner.read(queued, fh, 0, 100).unwrap(), Bytes::from("hello world"));
+    }
+
+    #[test]
+    fn upload_workers_survive_panics() {
+        let mount = TestMount::new("upload-panic", MemoryDrive::new());
+        let inner = mount.inner();
+        mount.drive.panic("create_file_with_proof", usize::MAX);
+        // more than there are workers
+        for name in ["a", "b", "c"] {
+            mount.write(FUSE_ROOT_ID, name, name.as_bytes());
+        }
+        wait_for("failed uploads", || {
+            let status = inner.upload_queue.status();
+            status.len() == 3 && status.iter().all(|status| matches!(status.phase, UploadPhase::Failed))
+        });
+        mount.drive.panic("create_file_with_proof", 0);
+        let ino = mount.upload(FUSE_ROOT_ID, "d", b"d");
+        assert!(!inner.file_id(ino).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncates_uploaded_file() {
+        let drive = MemoryDrive::new();
+        drive.add_folder("", "d");
+        let mount = TestMount::new("truncate", drive);
+        let inner = mount.inner();
+        let dir = mount.ino(FUSE_ROOT_ID, "d");
+        let ino = mount.upload(FUSE_ROOT_ID, "a", b"hello world");
+        let old_id = inner.file_id(ino).unwrap();
+        let content = |ino: u64| {
+            wait_for("re-upload", || !inner.file_id(ino).unwrap().is_empty());
+            mount.drive.content(&inner.file_id(ino).unwrap()).unwrap_or_default()
+        };
+
+        let changes = AttrChanges {
+            size: Some(5),
+            ..AttrChanges::default()
+        };
+        assert_eq!(inner.setattr(ino, None, changes).unwrap().size, 5);
+        assert_eq!(content(ino), Bytes::from("hello"));
+        // the old file goes to the trash once its replacement is uploaded
+        assert!(mount.drive.is_trashed(&old_id));
+        inner.truncate(ino, None, 8).unwrap();
+        assert_eq!(content(ino), Bytes::from(&b"hello\0\0\0"[..]));
+        assert_eq!(mount.names(""), vec!["a", "d"]);
+
+        // data not uploaded yet is truncated in place
+        let (ino, fh) = mount.create(FUSE_ROOT_ID, "b", b"hello world");
+        inner.truncate(ino, Some(fh), 5).unwrap();
+        mount.release(ino, fh);
+        assert_eq!(content(ino), Bytes::from("hello"));
+
+        assert!(matches!(inner.truncate(dir, None, 0), Err(Error::IsDirectory)));
+    }
+
+    #[test]
+    fn copies_into_created_files_on_server() {
+        let drive = MemoryDrive::new();
+        let src_id = drive.add_file("", "src", "copied data").id;
+        let mount = TestMount::new("copy", drive);
+        let inner = mount.inner();
+        let src = mount.ino(FUSE_ROOT_ID, "src");
+        // uploading the destination would fail
+        mount.drive.fail("create_file_with_proof", usize::MAX);
+
+        let (dest, fh) = mount.create(FUSE_ROOT_ID, "dest", b"");
+        assert_eq!(inner.copy_file_range(src, 0, dest, 0, 1 << 20).unwrap(), 11);
+        mount.release(dest, fh);
+        assert!(inner.upload_queue.status().is_empty());
+        let dest_id = inner.file_id(dest).unwrap();
+        assert_ne!(dest_id, src_id);
+        assert_eq!(mount.drive.get(&dest_id).unwrap().name, "dest");
+        assert_eq!(mount.drive.content(&dest_id).unwrap(), Bytes::from("copied data"));
+
+        // written data isn't copied over
+        let (written, fh) = mount.create(FUSE_ROOT_ID, "written", b"x");
+        assert!(matches!(
+            inner.copy_file_range(src, 0, written, 0, 1 << 20),
+            Err(Error::NotSupported)
+        ));
+        mount.release(written, fh);
+        // only whole files are copied on the server
+        let (part, fh) = mount.create(FUSE_ROOT_ID, "part", b"");
+        assert!(matches!(
+            inner.copy_file_range(src, 1, part, 0, 1 << 20),
+            Err(Error::NotSupported)
+        ));
+        mount.release(part, fh);
+    }
+
+    #[test]
+    fn truncate_updates_other_handles() {
+        let mount = TestMount::new("truncate-handles", MemoryDrive::new());
+        let inner = mount.inner();
+        let ino = mount.upload(FUSE_ROOT_ID, "f", b"hello world");
+        let old_id = inner.file_id(ino).unwrap();
+        let handles = [inner.open(ino).unwrap().unwrap(), inner.open(ino).unwrap().unwrap()];
+        for fh in handles {
+            assert_eq!(inner.read(ino, fh, 0, 100).unwrap(), Bytes::from("hello world"));
+        }
+
+        inner.truncate(ino, None, 16).unwrap();
+        wait_for("upload", || {
+            let id = inner.file_id(ino).unwrap();
+            !id.is_empty() && id != old_id
+        });
+        for fh in handles {
+            assert_eq!(inner.read(ino, fh, 0, 100).unwrap(), Bytes::from(&b"hello world\0\0\0\0\0"[..]));
+        }
+    }
+
+    #[test]
+    fn setattr_keeps_times_locally() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", "a", "aaa");
+        let mount = TestMount::new("setattr", drive);
+        let inner = mount.inner();
+        let ino = mount.ino(FUSE_ROOT_ID, "a");
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(1000);
+        let changes = AttrChanges {
+            mode: Some(0o100600),
+            mtime: Some(mtime),
+            ..AttrChanges::default()
+        };
+        let attr = inner.setattr(ino, None, changes).unwrap();
+        assert_eq!(attr.mtime, mtime);
+        // permissions aren't stored without --store-permissions
+        assert_ne!(attr.perm, 0o600);
+        assert_eq!(inner.lookup(FUSE_ROOT_ID, OsStr::new("a")).unwrap().mtime, mtime);
+    }
+
+    #[test]
+    fn rename_overwrites_target() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", "a", "aaa");
+        let old = drive.add_file("", "b", "bbb");
+        let mount = TestMount::new("rename-overwrite", drive);
+        let inner = mount.inner();
+        let a = mount.ino(FUSE_ROOT_ID, "a");
+
+        inner
+            .rename(FUSE_ROOT_ID, OsStr::new("a"), FUSE_ROOT_ID, OsStr::new("b"), 0)
+            .unwrap();
+        assert_eq!(mount.names(""), vec!["b"]);
+        let b = mount.drive.find("", "b").unwrap();
+        assert_eq!(mount.drive.content(&b.id).unwrap(), Bytes::from("aaa"));
+        assert!(mount.drive.is_trashed(&old.id));
+        assert_eq!(mount.ino(FUSE_ROOT_ID, "b"), a);
+        assert!(matches!(inner.lookup(FUSE_ROOT_ID, OsStr::new("a")), Err(Error::ChildNotFound)));
+    }
+
+    #[test]
+    fn failed_rename_restores_target() {
+        let drive = MemoryDrive::new();
+        let file = drive.add_file("", "a", "aaa");
+        let dir = drive.add_folder("", "d");
+        let target = drive.add_file(&dir.id, "b", "bbb");
+        let mount = TestMount::new("rename-failed", drive);
+        let inner = mount.inner();
+        let a = mount.ino(FUSE_ROOT_ID, "a");
+        let d = mount.ino(FUSE_ROOT_ID, "d");
+        let b = mount.ino(d, "b");
+        let rename = || inner.rename(FUSE_ROOT_ID, OsStr::new("a"), d, OsStr::new("b"), 0);
+
+        for method in ["rename_file", "move_file"] {
+            mount.drive.fail(method, 1);
+            assert!(matches!(rename(), Err(Error::ApiCallFailed)), "{} failed", method);
+            assert_eq!(mount.names(""), vec!["a", "d"]);
+            assert_eq!(mount.names(&dir.id), vec!["b"]);
+            assert_eq!(mount.drive.get(&file.id).unwrap().parent_id, "");
+            assert!(!mount.drive.is_trashed(&target.id));
+            assert_eq!(mount.ino(FUSE_ROOT_ID, "a"), a);
+            assert_eq!(mount.ino(d, "b"), b);
+        }
+
+        rename().unwrap();
+        assert_eq!(mount.names(""), vec!["d"]);
+        assert_eq!(mount.names(&dir.id), vec!["b"]);
+        assert!(mount.drive.is_trashed(&target.id));
+        assert_eq!(mount.ino(d, "b"), a);
+    }
+
+    #[test]
+    fn dot_files_are_listed() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", ".a.swp", "aaa");
+        drive.add_file("", "._a", "aaa");
+        let mount = TestMount::new("dot-files", drive);
+        let inner = mount.inner();
+
+        let entries = inner.readdir(FUSE_ROOT_ID, 0).unwrap();
+        assert_eq!(
+            entries.iter().map(|(_, _, name)| name.as_str()).collect::<Vec<_>>(),
+            vec![".", "..", "._a", ".a.swp"]
+        );
+        assert_eq!(inner.lookup(FUSE_ROOT_ID, OsStr::new(".a.swp")).unwrap().size, 3);
+        // macOS metadata files are never looked up
+        assert!(matches!(inner.lookup(FUSE_ROOT_ID, OsStr::new("._a")), Err(Error::ChildNotFound)));
+        assert!(matches!(inner.lookup(FUSE_ROOT_ID, OsStr::new(".DS_Store")), Err(Error::ChildNotFound)));
+    }
+
+    #[test]
+    fn rename_noreplace_keeps_target() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", "a", "aaa");
+        drive.add_file("", "b", "bbb");
+        let mount = TestMount::new("rename-noreplace", drive);
+        let inner = mount.inner();
+
+        let res = inner.rename(
+            FUSE_ROOT_ID,
+            OsStr::new("a"),
+            FUSE_ROOT_ID,
+            OsStr::new("b"),
+            libc::RENAME_NOREPLACE,
+        );
+        assert!(matches!(res, Err(Error::Exists)));
+        assert_eq!(mount.names(""), vec!["a", "b"]);
+        let b = mount.drive.find("", "b").unwrap();
+        assert_eq!(mount.drive.content(&b.id).unwrap(), Bytes::from("bbb"));
+
+        // nothing to replace
+        inner
+            .rename(
+                FUSE_ROOT_ID,
+                OsStr::new("a"),
+                FUSE_ROOT_ID,
+                OsStr::new("c"),
+                libc::RENAME_NOREPLACE,
+            )
+            .unwrap();
+        assert_eq!(mount.names(""), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn rename_checks_target_type() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", "a", "aaa");
+        let d = drive.add_folder("", "d");
+        drive.add_file(&d.id, "x", "x");
+        drive.add_folder("", "e");
+        let mount = TestMount::new("rename-types", drive);
+        let inner = mount.inner();
+        let rename = |name: &str, new_name: &str| {
+            inner.rename(FUSE_ROOT_ID, OsStr::new(name), FUSE_ROOT_ID, OsStr::new(new_name), 0)
+        };
+
+        assert!(matches!(rename("a", "d"), Err(Error::IsDirectory)));
+        assert!(matches!(rename("d", "a"), Err(Error::NotDirectory)));
+        assert!(matches!(rename("e", "d"), Err(Error::NotEmpty)));
+        // an empty folder is replaced
+        rename("d", "e").unwrap();
+        assert_eq!(mount.names(""), vec!["a", "e"]);
+        assert_eq!(mount.names(&d.id), vec!["x"]);
+    }
+
+    #[test]
+    fn rename_rejects_invalid_requests() {
+        let drive = MemoryDrive::new();
+        drive.add_file("", "a", "aaa");
+        drive.add_folder("", "d");
+        let mount = TestMount::new("rename-invalid", drive);
+        let inner = mount.inner();
+        let d = mount.ino(FUSE_ROOT_ID, "d");
+        let rename = |name: &str, new_parent: u64, new_name: &str, flags: u32| {
+            inner.rename(FUSE_ROOT_ID, OsStr::new(name), new_parent, OsStr::new(new_name), flags)
+        };
+
+        assert!(matches!(
+            rename("a", FUSE_ROOT_ID, "d", libc::RENAME_EXCHANGE),
+            Err(Error::NotSupported)
+        ));
+        assert!(matches!(
+            rename("a", FUSE_ROOT_ID, "b", libc::RENAME_WHITEOUT),
+            Err(Error::InvalidArgument)
+        ));
+        // a folder can't go into itself
+        assert!(matches!(rename("d", d, "e", 0), Err(Error::InvalidArgument)));
+        assert!(matches!(rename("c", FUSE_ROOT_ID, "b", 0), Err(Error::ChildNotFound)));
+        assert_eq!(mount.names(""), vec!["a", "d"]);
+    }
+
+    #[test]
+    fn rmdir_refuses_non_empty_folder() {
+        let drive = MemoryDrive::new();
+        let d = drive.add_folder("", "d");
+        let mount = TestMount::new("rmdir", drive);
+        let inner = mount.inner();
+        let d_ino = mount.ino(FUSE_ROOT_ID, "d");
+        assert_eq!(inner.readdir(d_ino, 0).unwrap().len(), 2);
+
+        // added behind the cached empty listing
+        let x = mount.drive.add_file(&d.id, "x", "x");
+        assert!(matches!(inner.rmdir(FUSE_ROOT_ID, OsStr::new("d")), Err(Error::NotEmpty)));
+        assert!(mount.drive.get(&d.id).is_some());
+
+        inner.unlink(d_ino, OsStr::new("x")).unwrap();
+        assert!(mount.drive.get(&x.id).is_none());
+        inner.rmdir(FUSE_ROOT_ID, OsStr::new("d")).unwrap();
+        assert!(mount.drive.get(&d.id).is_none());
+        assert!(matches!(inner.lookup(FUSE_ROOT_ID, OsStr::new("d")), Err(Error::ChildNotFound)));
+        assert!(matches!(inner.rmdir(FUSE_ROOT_ID, OsStr::new("d")), Err(Error::ChildNotFound)));
+    }
+
+    #[test]
+    fn unlink_refuses_folder() {
+        let drive = MemoryDrive::new();
+        let d = drive.add_folder("", "d");
+        drive.add_file("", "a", "aaa");
+        let mount = TestMount::new("unlink", drive);
+        let inner = mount.inner();
+
+        assert!(matches!(inner.unlink(FUSE_ROOT_ID, OsStr::new("d")), Err(Error::IsDirectory)));
+        assert!(mount.drive.get(&d.id).is_some());
+        assert!(matches!(inner.rmdir(FUSE_ROOT_ID, OsStr::new("a")), Err(Error::NotDirectory)));
+        inner.unlink(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+        assert_eq!(mount.names(""), vec!["d"]);
+        assert!(matches!(inner.unlink(FUSE_ROOT_ID, OsStr::new("a")), Err(Error::ChildNotFound)));
+    }
+}